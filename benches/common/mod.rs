@@ -0,0 +1,184 @@
+//! The parts of the dispatch benchmarks that are shared by both of them. Each variant is built
+//! into its own benchmark, so that the code size it reports only contains that variant.
+
+use picomenu::*;
+use std::hint::black_box;
+use std::time::Instant;
+
+/// Lines that are sent before the measurement starts, e.g. to fill the caches.
+const WARM_UP_LINES: usize = 10_000;
+const LINES: usize = 100_000;
+
+/// The lines that every variant dispatches, together with a description of each scenario.
+pub const SCENARIOS: [(&str, &[&str]); 3] = [
+    ("first registered", &["adc\n"]),
+    ("last registered", &["wifi\n"]),
+    ("mixed commands", &["adc\n", "led\n", "sleep\n", "wifi\n"]),
+];
+
+/// Replays the same lines until `WARM_UP_LINES` and then `LINES` lines have been sent.
+pub struct ReplayIo {
+    lines: &'static [&'static str],
+    sent: usize,
+    /// The time at which the warm-up was over.
+    start: Option<Instant>,
+}
+
+impl ReplayIo {
+    pub fn new(lines: &'static [&'static str]) -> Self {
+        Self {
+            lines,
+            sent: 0,
+            start: None,
+        }
+    }
+}
+
+impl IoDevice for ReplayIo {
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), IoDeviceError> {
+        black_box(data);
+        Ok(())
+    }
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, IoDeviceError> {
+        if self.sent == WARM_UP_LINES {
+            self.start = Some(Instant::now());
+        }
+        if self.sent == WARM_UP_LINES + LINES {
+            return Err(IoDeviceError::Disconnected);
+        }
+
+        let line = self.lines[self.sent % self.lines.len()].as_bytes();
+        self.sent += 1;
+        data[..line.len()].copy_from_slice(line);
+        Ok(line.len())
+    }
+}
+
+macro_rules! commands {
+    ($($ty:ident $name:literal),* $(,)?) => {
+        $(
+            struct $ty {}
+            impl<IO: IoDevice> Command<IO, ()> for $ty {
+                fn name() -> &'static str {
+                    $name
+                }
+
+                fn help_string() -> &'static str {
+                    "Does nothing"
+                }
+
+                async fn execute(
+                    args: Option<&str>,
+                    _output: &mut Output<'_, IO>,
+                    _state: &mut (),
+                ) -> Result<(), MenuError> {
+                    black_box(args);
+                    Ok(())
+                }
+            }
+        )*
+
+        /// Registers all 64 commands of the benchmark.
+        pub fn register<'d>(menu: impl Menu<ReplayIo, ()> + 'd) -> impl Menu<ReplayIo, ()> + 'd {
+            menu$(.with_command::<$ty>().unwrap())*
+        }
+    };
+}
+
+commands!(
+    Cmd00 "adc", Cmd01 "alarm", Cmd02 "baud", Cmd03 "beep", Cmd04 "blink", Cmd05 "boot",
+    Cmd06 "bright", Cmd07 "calib", Cmd08 "can", Cmd09 "clock", Cmd10 "config", Cmd11 "cpu",
+    Cmd12 "dac", Cmd13 "date", Cmd14 "debug", Cmd15 "dfu", Cmd16 "dma", Cmd17 "echo",
+    Cmd18 "eeprom", Cmd19 "erase", Cmd20 "fan", Cmd21 "flash", Cmd22 "freq", Cmd23 "gain",
+    Cmd24 "gpio", Cmd25 "heap", Cmd26 "i2c", Cmd27 "id", Cmd28 "imu", Cmd29 "irq",
+    Cmd30 "led", Cmd31 "level", Cmd32 "load", Cmd33 "log", Cmd34 "mac", Cmd35 "mem",
+    Cmd36 "mode", Cmd37 "motor", Cmd38 "mute", Cmd39 "net", Cmd40 "offset", Cmd41 "ping",
+    Cmd42 "power", Cmd43 "pwm", Cmd44 "rate", Cmd45 "reboot", Cmd46 "reset", Cmd47 "rtc",
+    Cmd48 "save", Cmd49 "scan", Cmd50 "sensor", Cmd51 "serial", Cmd52 "sleep", Cmd53 "spi",
+    Cmd54 "stack", Cmd55 "stats", Cmd56 "temp", Cmd57 "time", Cmd58 "trace", Cmd59 "uart",
+    Cmd60 "uptime", Cmd61 "usb", Cmd62 "volt", Cmd63 "wifi",
+);
+
+/// The sizes of a menu and of its `run()` future, together with the time at which it stopped.
+pub struct RunResult {
+    menu_size: usize,
+    run_size: usize,
+    end: Instant,
+}
+
+/// Runs `menu` until the device disconnects.
+pub fn run<M: Menu<ReplayIo, ()>>(menu: M) -> RunResult {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+
+    let menu_size = size_of_val(&menu);
+    let run = menu.run();
+    let run_size = size_of_val(&run);
+    runtime.block_on(run).unwrap();
+    RunResult {
+        menu_size,
+        run_size,
+        end: Instant::now(),
+    }
+}
+
+pub fn report(variant: &str, description: &str, device: &ReplayIo, result: RunResult) {
+    let per_line = (result.end - device.start.unwrap()) / LINES as u32;
+    println!(
+        "{:<24} {:>8} ns/line {:>6} B menu {:>6} B run future",
+        format!("{variant}, {description}"),
+        per_line.as_nanos(),
+        result.menu_size,
+        result.run_size,
+    );
+}
+
+/// Prints the size of the executable code of the running benchmark. Both benchmarks share all
+/// code except for their variant, so the difference between their sizes is the difference in
+/// code size between the variants.
+pub fn report_code_size(variant: &str) {
+    match std::env::current_exe()
+        .ok()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|elf| code_size(&elf))
+    {
+        Some(size) => println!("{:<24} {size:>8} B code", format!("{variant}, executable")),
+        None => println!("{variant}: code size is only measured for 64-bit ELF executables"),
+    }
+}
+
+/// Returns the total size of the executable sections of a 64-bit little-endian ELF file.
+fn code_size(elf: &[u8]) -> Option<u64> {
+    const SHF_EXECINSTR: u64 = 0x4;
+
+    let u16_at = |offset: usize| {
+        Some(u16::from_le_bytes(
+            elf.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+    let u64_at = |offset: usize| {
+        Some(u64::from_le_bytes(
+            elf.get(offset..offset + 8)?.try_into().ok()?,
+        ))
+    };
+
+    // Magic number, 64-bit class and little-endian data encoding
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+
+    let section_headers = usize::try_from(u64_at(0x28)?).ok()?;
+    let header_size = usize::from(u16_at(0x3a)?);
+    let header_count = usize::from(u16_at(0x3c)?);
+
+    let mut size = 0;
+    for i in 0..header_count {
+        let header = section_headers + i * header_size;
+        if u64_at(header + 0x08)? & SHF_EXECINSTR != 0 {
+            size += u64_at(header + 0x20)?;
+        }
+    }
+    Some(size)
+}
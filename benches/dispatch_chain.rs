@@ -0,0 +1,42 @@
+//! Measures dispatching commands by comparing their names with every registered command in
+//! turn, for a menu with 64 commands. Compare with the `dispatch_table` benchmark, which uses
+//! `Menu::with_dispatch_table` instead.
+//!
+//! Run using `cargo bench`. Besides the time needed to dispatch a line, this prints the size of
+//! the menu, of its `run()` future and of the code of the benchmark. Every scenario dispatches a
+//! number of lines before the measurement starts, so that it runs with warmed up caches.
+
+mod common;
+
+use common::*;
+use picomenu::*;
+
+#[inline(never)]
+fn chain_menu<'d>(
+    device: &'d mut ReplayIo,
+    state: &'d mut (),
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+) -> impl Menu<ReplayIo, ()> + 'd {
+    let menu = make_menu(device, state, input_buffer, output_buffer);
+    register(menu)
+}
+
+fn main() {
+    for (description, lines) in SCENARIOS {
+        let mut state = ();
+        let mut input_buffer = [0; 128];
+        let mut output_buffer = [0; 128];
+
+        let mut device = ReplayIo::new(lines);
+        let result = run(chain_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        ));
+        report("chain", description, &device, result);
+    }
+
+    report_code_size("chain");
+}
@@ -0,0 +1,50 @@
+//! Measures dispatching commands through a sorted table (see `Menu::with_dispatch_table`), for
+//! a menu with 64 commands. Compare with the `dispatch_chain` benchmark, which compares names
+//! with every registered command in turn instead.
+//!
+//! Run using `cargo bench`. Besides the time needed to dispatch a line, this prints the size of
+//! the menu, of its `run()` future and of the code of the benchmark. Every scenario dispatches a
+//! number of lines before the measurement starts, so that it runs with warmed up caches. The
+//! entries of the table are borrowed by the menu, so they are not part of either size.
+
+mod common;
+
+use common::*;
+use picomenu::*;
+
+/// The number of entries that the dispatch table needs, one for each command and one for the
+/// help command.
+const DISPATCH_ENTRIES: usize = 65;
+
+#[inline(never)]
+fn table_menu<'d>(
+    device: &'d mut ReplayIo,
+    state: &'d mut (),
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+    dispatch_table: &'d mut [DispatchEntry],
+) -> impl Menu<ReplayIo, ()> + 'd {
+    let menu = make_menu(device, state, input_buffer, output_buffer);
+    register(menu).with_dispatch_table(dispatch_table).unwrap()
+}
+
+fn main() {
+    for (description, lines) in SCENARIOS {
+        let mut state = ();
+        let mut input_buffer = [0; 128];
+        let mut output_buffer = [0; 128];
+        let mut dispatch_table = [DispatchEntry::EMPTY; DISPATCH_ENTRIES];
+
+        let mut device = ReplayIo::new(lines);
+        let result = run(table_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+            &mut dispatch_table,
+        ));
+        report("table", description, &device, result);
+    }
+
+    report_code_size("table");
+}
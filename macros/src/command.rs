@@ -0,0 +1,108 @@
+//! Implementation of the `command` attribute.
+
+use crate::common::{Arg, CommandOptions, output_io_type, parse_args};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{FnArg, ItemFn, Pat, Type, parse_quote, spanned::Spanned};
+
+/// How a parameter of the command function receives its value.
+enum Param {
+    Output,
+    State,
+    Arg(Arg),
+}
+
+pub(crate) fn expand(options: CommandOptions, function: ItemFn) -> syn::Result<TokenStream> {
+    let sig = &function.sig;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            sig.fn_token.span(),
+            "commands must be `async fn`s",
+        ));
+    }
+
+    let fn_ident = &sig.ident;
+    let vis = &function.vis;
+
+    let mut io_type: Option<Type> = None;
+    let mut state_type: Option<Type> = None;
+    let mut params = Vec::new();
+    for input in &sig.inputs {
+        let FnArg::Typed(input) = input else {
+            return Err(syn::Error::new(input.span(), "commands cannot take `self`"));
+        };
+        let ident = match &*input.pat {
+            Pat::Ident(pat) => pat.ident.clone(),
+            _ => format_ident!("arg{}", params.len()),
+        };
+
+        params.push(match &*input.ty {
+            Type::Reference(reference) if reference.mutability.is_some() => {
+                if let Some(io) = output_io_type(&reference.elem) {
+                    io_type = Some(io);
+                    Param::Output
+                } else {
+                    state_type = Some((*reference.elem).clone());
+                    Param::State
+                }
+            }
+            ty => Param::Arg(Arg::new(ident, ty)),
+        });
+    }
+
+    let mut generics = sig.generics.clone();
+    let io_type = io_type.unwrap_or_else(|| {
+        generics
+            .params
+            .push(parse_quote!(__IO: ::picomenu::IoDevice));
+        parse_quote!(__IO)
+    });
+    let state_type = state_type.unwrap_or_else(|| {
+        generics.params.push(parse_quote!(__S));
+        parse_quote!(__S)
+    });
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let describe = options.describe(
+        fn_ident.to_string(),
+        &state_type,
+        &function.attrs,
+        fn_ident.span(),
+    )?;
+
+    let args: Vec<_> = params
+        .iter()
+        .filter_map(|param| match param {
+            Param::Arg(arg) => Some(arg),
+            _ => None,
+        })
+        .collect();
+    let parse_args = parse_args(&format_ident!("__args"), &args);
+    let call_args = params.iter().map(|param| match param {
+        Param::Output => quote!(__output),
+        Param::State => quote!(__state),
+        Param::Arg(arg) => {
+            let ident = arg.ident();
+            quote!(#ident)
+        }
+    });
+
+    Ok(quote! {
+        #function
+
+        #[allow(non_camel_case_types)]
+        #vis struct #fn_ident {}
+
+        impl #impl_generics ::picomenu::Command<#io_type, #state_type> for #fn_ident #where_clause {
+            #describe
+
+            async fn execute(
+                __args: Option<&str>,
+                __output: &mut ::picomenu::Output<'_, #io_type>,
+                __state: &mut #state_type,
+            ) -> Result<(), ::picomenu::MenuError> {
+                #parse_args
+                #fn_ident(#(#call_args),*).await
+            }
+        }
+    })
+}
@@ -0,0 +1,187 @@
+//! Implementation of the `CommandSet` derive.
+
+use crate::common::{Arg, CommandOptions, parse_args};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Fields, LitStr, Path, Type, parse_quote, spanned::Spanned};
+
+pub(crate) fn expand(input: DeriveInput) -> syn::Result<TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new(
+            input.ident.span(),
+            "command sets must be enums",
+        ));
+    };
+
+    let mut handler: Option<Path> = None;
+    let mut state_type: Option<Type> = None;
+    let mut prefix: Option<LitStr> = None;
+    let mut category: Option<LitStr> = None;
+    for attr in input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("command_set"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("handler") {
+                handler = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("state") {
+                state_type = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("prefix") {
+                prefix = Some(meta.value()?.parse()?);
+            } else if meta.path.is_ident("category") {
+                category = Some(meta.value()?.parse()?);
+            } else {
+                return Err(meta.error("unsupported command set option"));
+            }
+            Ok(())
+        })?;
+    }
+    let handler = handler.ok_or_else(|| {
+        syn::Error::new(
+            input.ident.span(),
+            "command sets need a `#[command_set(handler = ...)]` function",
+        )
+    })?;
+
+    let enum_ident = &input.ident;
+    let vis = &input.vis;
+    let mut generics = input.generics.clone();
+    generics
+        .params
+        .push(parse_quote!(__IO: ::picomenu::IoDevice));
+    let state_generic = state_type.is_none().then(|| quote!(, __S));
+    let state_type = state_type.unwrap_or_else(|| {
+        generics.params.push(parse_quote!(__S));
+        parse_quote!(__S)
+    });
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
+    let (_, enum_generics, _) = input.generics.split_for_impl();
+
+    let mut commands = Vec::new();
+    let mut command_impls = Vec::new();
+    for variant in &data.variants {
+        let mut options = CommandOptions::default();
+        for attr in variant
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("command_set"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if options.parse(&meta)? {
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported command option"))
+                }
+            })?;
+        }
+
+        let variant_ident = &variant.ident;
+        let describe = options.describe(
+            snake_case(&variant_ident.to_string()),
+            &state_type,
+            &variant.attrs,
+            variant_ident.span(),
+        )?;
+
+        let args: Vec<Arg> = match &variant.fields {
+            Fields::Unit => Vec::new(),
+            Fields::Named(fields) => fields
+                .named
+                .iter()
+                .map(|field| Arg::new(field.ident.clone().unwrap(), &field.ty))
+                .collect(),
+            Fields::Unnamed(fields) => {
+                return Err(syn::Error::new(
+                    fields.span(),
+                    "command set variants need named fields",
+                ));
+            }
+        };
+        let arg_refs: Vec<&Arg> = args.iter().collect();
+        let parse_args = parse_args(&format_ident!("__args"), &arg_refs);
+        let fields = args.iter().map(Arg::ident);
+
+        let command = format_ident!("__{}{}", enum_ident, variant_ident);
+        command_impls.push(quote! {
+            #[doc(hidden)]
+            #vis struct #command {}
+
+            impl<__IO: ::picomenu::IoDevice #state_generic> ::picomenu::Command<__IO, #state_type>
+                for #command
+            {
+                #describe
+
+                async fn execute(
+                    __args: Option<&str>,
+                    __output: &mut ::picomenu::Output<'_, __IO>,
+                    __state: &mut #state_type,
+                ) -> Result<(), ::picomenu::MenuError> {
+                    #parse_args
+                    #handler(#enum_ident::#variant_ident { #(#fields),* }, __output, __state).await
+                }
+            }
+        });
+        commands.push(command);
+    }
+
+    let register = match commands.split_last() {
+        Some((last, others)) => quote! {
+            menu #(.with_command::<#others>()?)*.with_command::<#last>()
+        },
+        None => quote!(Ok(menu)),
+    };
+    let prefix = match prefix {
+        Some(prefix) => quote!(Some(#prefix)),
+        None => quote!(None),
+    };
+    let category = match category {
+        Some(category) => quote!(Some(#category)),
+        None => quote!(None),
+    };
+
+    Ok(quote! {
+        #(#command_impls)*
+
+        impl #impl_generics ::picomenu::CommandSet<__IO, #state_type> for #enum_ident #enum_generics
+            #where_clause
+        {
+            fn register(
+                menu: impl ::picomenu::SubMenu<__IO, #state_type>,
+            ) -> Result<impl ::picomenu::SubMenu<__IO, #state_type>, ::picomenu::RegistrationError> {
+                #register
+            }
+
+            fn prefix() -> Option<&'static str> {
+                #prefix
+            }
+
+            fn category() -> Option<&'static str> {
+                #category
+            }
+        }
+    })
+}
+
+/// Converts a variant name such as `SetGain` into a command name such as `set_gain`, which
+/// matches the names that the `command` attribute derives from function names.
+fn snake_case(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    let mut snake = String::new();
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_uppercase() {
+            // Runs of capitals are acronyms, which form a single word up to the capital that
+            // starts the next one, e.g. `http_get` for `HTTPGet`
+            let starts_word = i > 0
+                && (!chars[i - 1].is_uppercase()
+                    || chars.get(i + 1).is_some_and(|next| next.is_lowercase()));
+            if starts_word {
+                snake.push('_');
+            }
+            snake.extend(c.to_lowercase());
+        } else {
+            snake.push(c);
+        }
+    }
+    snake
+}
@@ -0,0 +1,321 @@
+//! Code generation that is shared by the `command` attribute and the `CommandSet` derive.
+
+use proc_macro2::{Span, TokenStream};
+use quote::quote;
+use syn::{
+    Attribute, Expr, ExprArray, ExprLit, GenericArgument, Ident, Lit, LitInt, LitStr, Meta, Path,
+    PathArguments, Type, spanned::Spanned,
+};
+
+/// The options that describe a single command.
+#[derive(Default)]
+pub(crate) struct CommandOptions {
+    pub(crate) name: Option<LitStr>,
+    pub(crate) help: Option<LitStr>,
+    pub(crate) long_help: Option<LitStr>,
+    pub(crate) aliases: Vec<LitStr>,
+    pub(crate) category: Option<LitStr>,
+    pub(crate) hidden: bool,
+    pub(crate) required_level: Option<LitInt>,
+    pub(crate) hidden_when: Option<Path>,
+    pub(crate) enabled_when: Option<Path>,
+}
+
+impl CommandOptions {
+    /// Parses a single option, returning `false` if `meta` is not a command option.
+    pub(crate) fn parse(&mut self, meta: &syn::meta::ParseNestedMeta) -> syn::Result<bool> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("help") {
+            self.help = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("long_help") {
+            self.long_help = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("category") {
+            self.category = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("aliases") {
+            let aliases: ExprArray = meta.value()?.parse()?;
+            self.aliases = aliases
+                .elems
+                .iter()
+                .map(|alias| match alias {
+                    Expr::Lit(ExprLit {
+                        lit: Lit::Str(alias),
+                        ..
+                    }) => Ok(alias.clone()),
+                    _ => Err(syn::Error::new(
+                        alias.span(),
+                        "aliases must be string literals",
+                    )),
+                })
+                .collect::<syn::Result<_>>()?;
+        } else if meta.path.is_ident("hidden") {
+            self.hidden = true;
+        } else if meta.path.is_ident("required_level") {
+            self.required_level = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("hidden_when") {
+            self.hidden_when = Some(meta.value()?.parse()?);
+        } else if meta.path.is_ident("enabled_when") {
+            self.enabled_when = Some(meta.value()?.parse()?);
+        } else {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Generates the functions of `picomenu::Command` that describe the command. The help
+    /// string falls back to the doc comment in `attrs`.
+    pub(crate) fn describe(
+        &self,
+        default_name: String,
+        state_type: &Type,
+        attrs: &[Attribute],
+        span: Span,
+    ) -> syn::Result<TokenStream> {
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => LitStr::new(&default_name, span),
+        };
+        let help = match &self.help {
+            Some(help) => help.clone(),
+            None => doc_comment(attrs).ok_or_else(|| {
+                syn::Error::new(span, "commands need a `help` option or a doc comment")
+            })?,
+        };
+        let long_help = match &self.long_help {
+            Some(long_help) => quote!(Some(#long_help)),
+            None => quote!(None),
+        };
+        let category = match &self.category {
+            Some(category) => quote!(Some(#category)),
+            None => quote!(None),
+        };
+        let aliases = &self.aliases;
+        let hidden = self.hidden;
+        let required_level = match &self.required_level {
+            Some(level) => quote!(#level),
+            None => quote!(0),
+        };
+        let is_hidden = self.hidden_when.as_ref().map(|predicate| {
+            quote! {
+                fn is_hidden(__state: &#state_type) -> bool {
+                    #predicate(__state)
+                }
+            }
+        });
+        let is_enabled = self.enabled_when.as_ref().map(|predicate| {
+            quote! {
+                fn is_enabled(__state: &#state_type) -> bool {
+                    #predicate(__state)
+                }
+            }
+        });
+
+        Ok(quote! {
+            fn name() -> &'static str {
+                #name
+            }
+
+            fn help_string() -> &'static str {
+                #help
+            }
+
+            fn long_help() -> Option<&'static str> {
+                #long_help
+            }
+
+            fn aliases() -> &'static [&'static str] {
+                &[#(#aliases),*]
+            }
+
+            fn category() -> Option<&'static str> {
+                #category
+            }
+
+            fn hidden() -> bool {
+                #hidden
+            }
+
+            fn required_level() -> u8 {
+                #required_level
+            }
+
+            #is_hidden
+            #is_enabled
+        })
+    }
+}
+
+/// Joins the lines of the doc comment in `attrs`, if there is one.
+fn doc_comment(attrs: &[Attribute]) -> Option<LitStr> {
+    let lines: Vec<String> = attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta {
+            Meta::NameValue(doc) => match &doc.value {
+                Expr::Lit(ExprLit {
+                    lit: Lit::Str(line),
+                    ..
+                }) => Some(line.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+
+    let doc = lines.join(" ").trim().to_string();
+    (!doc.is_empty()).then(|| LitStr::new(&doc, Span::call_site()))
+}
+
+/// How an argument of a command receives its value.
+pub(crate) enum Arg {
+    /// A `bool` that is set by passing `--<name>`.
+    Flag(Ident),
+    /// A `&str` that receives a word as is.
+    Word(Ident),
+    /// An `Option<&str>` that receives a word as is.
+    OptionalWord(Ident),
+    /// An `Option<T>` that is parsed using `FromStr`.
+    Optional(Ident, Box<Type>),
+    /// A `T` that is parsed using `FromStr`.
+    Required(Ident, Box<Type>),
+}
+
+impl Arg {
+    pub(crate) fn new(ident: Ident, ty: &Type) -> Self {
+        match ty {
+            Type::Path(path) if path.path.is_ident("bool") => Arg::Flag(ident),
+            Type::Reference(reference) if is_str(&reference.elem) => Arg::Word(ident),
+            ty => match option_inner_type(ty) {
+                Some(Type::Reference(reference)) if is_str(&reference.elem) => {
+                    Arg::OptionalWord(ident)
+                }
+                Some(inner) => Arg::Optional(ident, Box::new(inner)),
+                None => Arg::Required(ident, Box::new(ty.clone())),
+            },
+        }
+    }
+
+    pub(crate) fn ident(&self) -> &Ident {
+        match self {
+            Arg::Flag(ident)
+            | Arg::Word(ident)
+            | Arg::OptionalWord(ident)
+            | Arg::Optional(ident, _)
+            | Arg::Required(ident, _) => ident,
+        }
+    }
+}
+
+/// Generates code that parses the whitespace separated words in `input` (an `Option<&str>`)
+/// into a variable for every argument. Flags can appear anywhere, the other arguments are
+/// assigned in order.
+pub(crate) fn parse_args(input: &Ident, args: &[&Arg]) -> TokenStream {
+    let flags: Vec<_> = args
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Flag(ident) => Some(ident),
+            _ => None,
+        })
+        .collect();
+    let flag_names = flags
+        .iter()
+        .map(|ident| format!("--{}", ident.to_string().replace('_', "-")));
+
+    let positionals: Vec<_> = args
+        .iter()
+        .filter(|arg| !matches!(arg, Arg::Flag(_)))
+        .collect();
+    let declarations = positionals.iter().map(|arg| {
+        let ident = arg.ident();
+        quote!(let mut #ident = None;)
+    });
+    let assignments = positionals.iter().enumerate().map(|(position, arg)| {
+        let ident = arg.ident();
+        let invalid = format!("Invalid argument <{ident}>");
+        let value = match arg {
+            Arg::Optional(_, ty) | Arg::Required(_, ty) => quote! {
+                __word
+                    .parse::<#ty>()
+                    .map_err(|_| ::picomenu::MenuError::Command(#invalid))?
+            },
+            _ => quote!(__word),
+        };
+        quote!(#position => #ident = Some(#value),)
+    });
+    let positional = if positionals.is_empty() {
+        quote!(return Err(::picomenu::MenuError::Command("Too many arguments"));)
+    } else {
+        quote! {
+            match __position {
+                #(#assignments)*
+                _ => return Err(::picomenu::MenuError::Command("Too many arguments")),
+            }
+            __position += 1;
+        }
+    };
+    let required = positionals.iter().filter_map(|arg| match arg {
+        Arg::Word(ident) | Arg::Required(ident, _) => {
+            let missing = format!("Missing argument <{ident}>");
+            Some(quote! {
+                let #ident = #ident.ok_or(::picomenu::MenuError::Command(#missing))?;
+            })
+        }
+        _ => None,
+    });
+    let position = (!positionals.is_empty()).then(|| quote!(let mut __position = 0usize;));
+
+    quote! {
+        #(let mut #flags = false;)*
+        #(#declarations)*
+        #position
+        for __word in #input.unwrap_or("").split_whitespace() {
+            match __word {
+                #(#flag_names => #flags = true,)*
+                _ => {
+                    #positional
+                }
+            }
+        }
+        #(#required)*
+    }
+}
+
+/// Returns `IO` if `ty` is `Output<'_, IO>`.
+pub(crate) fn output_io_type(ty: &Type) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Output" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    args.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    })
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_inner_type(ty: &Type) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    match args.args.first()? {
+        GenericArgument::Type(ty) => Some(ty.clone()),
+        _ => None,
+    }
+}
+
+fn is_str(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.path.is_ident("str"))
+}
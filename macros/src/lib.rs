@@ -0,0 +1,118 @@
+//! Procedural macros for picomenu. These are re-exported by picomenu when its `macros`
+//! feature is enabled (which it is by default), so there is no need to depend on this
+//! crate directly.
+
+mod command;
+mod command_set;
+mod common;
+
+use common::CommandOptions;
+use proc_macro::TokenStream;
+use syn::{DeriveInput, ItemFn, parse_macro_input};
+
+/// Turns an `async fn` into a command, by generating a struct with the same name as the
+/// function that implements `picomenu::Command` and calls the function.
+///
+/// ```ignore
+/// /// Sets the gain of a channel
+/// #[picomenu::command(name = "gain", aliases = ["g"], category = "Audio")]
+/// async fn set_gain<IO: IoDevice>(
+///     channel: u8,
+///     gain: Option<f32>,
+///     output: &mut Output<'_, IO>,
+///     state: &mut State,
+/// ) -> Result<(), MenuError> {
+///     // ...
+/// }
+///
+/// let menu = make_menu(/* ... */).with_command::<set_gain>()?;
+/// ```
+///
+/// The following options are supported, all of which are optional:
+/// - `name = "..."`: the name of the command, which defaults to the name of the function.
+/// - `help = "..."`: the help string, which defaults to the doc comment of the function.
+/// - `long_help = "..."`: the detailed description printed by `help <name>`.
+/// - `aliases = ["...", ...]`: alternative names of the command.
+/// - `category = "..."`: the category of the command.
+/// - `hidden`: hides the command from the default help output.
+/// - `required_level = ...`: the privilege level that is needed to run the command.
+/// - `hidden_when = ...`: a function taking `&State` that decides whether the command is
+///   hidden from the default help output, see `Command::is_hidden`.
+/// - `enabled_when = ...`: a function taking `&State` that decides whether the command can
+///   be run, see `Command::is_enabled`.
+///
+/// A parameter of type `&mut Output<'_, IO>` receives the output handle and any other
+/// `&mut` parameter receives the menu state. All remaining parameters are parsed from the
+/// whitespace separated arguments of the command: `bool` parameters are flags that are set
+/// by passing `--<name>` anywhere. The other parameters are assigned in order, where `&str`
+/// parameters receive a word as is, all other types are parsed using `FromStr` and
+/// `Option<T>` parameters are optional. Missing, invalid or surplus arguments result in a
+/// `MenuError::Command`.
+#[proc_macro_attribute]
+pub fn command(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut options = CommandOptions::default();
+    let parser = syn::meta::parser(|meta| {
+        if options.parse(&meta)? {
+            Ok(())
+        } else {
+            Err(meta.error("unsupported command option"))
+        }
+    });
+    parse_macro_input!(attr with parser);
+
+    let function = parse_macro_input!(item as ItemFn);
+    command::expand(options, function)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Turns every variant of an enum into a command, which parses its arguments into the fields
+/// of the variant and passes the result to a single handler function. The enum implements
+/// `picomenu::CommandSet`, so all of its commands can be registered at once as a module using
+/// `Menu::with_module`.
+///
+/// ```ignore
+/// #[derive(CommandSet)]
+/// #[command_set(handler = handle, state = State)]
+/// enum Cmd<'a> {
+///     /// Shows version
+///     Version,
+///     /// Says hello
+///     Hello { name: &'a str },
+///     /// Resets the device
+///     #[command_set(hidden)]
+///     Reset { force: bool },
+/// }
+///
+/// async fn handle<IO: IoDevice>(
+///     cmd: Cmd<'_>,
+///     output: &mut Output<'_, IO>,
+///     state: &mut State,
+/// ) -> Result<(), MenuError> {
+///     match cmd {
+///         // ...
+///     }
+/// }
+///
+/// let menu = make_menu(/* ... */).with_module::<Cmd>()?;
+/// ```
+///
+/// The enum supports the following options:
+/// - `handler = ...`: the async function that handles the commands, which is required.
+/// - `state = ...`: the type of the menu state. If it is not given, the handler needs to
+///   accept any state.
+/// - `prefix = "..."`: a prefix that needs to be entered before the name of every command.
+/// - `category = "..."`: the category of all commands that do not specify one.
+///
+/// Variants support the same options as the `command` attribute, where the name defaults to
+/// the name of the variant in snake_case, e.g. `set_gain` for `SetGain` and `read_adc` for
+/// `ReadADC`. Variants can either
+/// be unit variants or have named fields, which are parsed like the parameters of functions
+/// with the `command` attribute.
+#[proc_macro_derive(CommandSet, attributes(command_set))]
+pub fn derive_command_set(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    command_set::expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
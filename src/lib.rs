@@ -1,25 +1,71 @@
 #![no_std]
 
-use core::future::Future;
+use core::cell::Cell;
+use core::future::{Future, poll_fn, ready};
 use core::marker::PhantomData;
+use core::pin::{Pin, pin};
 use core::str::{self, Utf8Error};
+use core::task::{Context, Poll};
 use ufmt::uWrite;
 
+#[cfg(feature = "macros")]
+pub use picomenu_macros::{CommandSet, command};
+
 /// These are errors that an `IoDevice` may throw when it is requested to
 /// perform an operation.
-#[derive(Debug, defmt::Format, PartialEq)]
+///
+/// The documentation of each variant describes how the menu reacts when it is returned by
+/// `IoDevice::read_packet`. Errors returned by `IoDevice::write_packet` are passed on to the
+/// `Command` that was writing, and are rendered by the `ErrorRenderer` if the command fails.
+#[derive(Debug, defmt::Format, PartialEq, Clone, Copy)]
 pub enum IoDeviceError {
     /// This error needs to be thrown when the `IoDevice` has disconnected
     /// and can therefore no longer provide input.
+    ///
+    /// The menu stops running.
     Disconnected,
 
     /// This error indicates that the `IoDevice` has experienced an internal
     /// buffer overflow condition.
+    ///
+    /// The menu reports the error and keeps running. If part of a line had already been
+    /// received, it is dropped together with all input up to the next line terminator. When
+    /// the device dropped the terminator of that line as well, this includes the next line.
     BufferOverflow,
+
+    /// The `IoDevice` gave up waiting for data.
+    ///
+    /// The menu silently retries the read without dropping any input, yielding to the
+    /// executor in between. The idle timeout of the menu keeps running while it does.
+    Timeout,
+
+    /// The operation could not be completed right now, but may succeed when retried.
+    ///
+    /// The menu silently retries the read without dropping any input, yielding to the
+    /// executor in between. The idle timeout of the menu keeps running while it does.
+    WouldBlock,
+
+    /// The `IoDevice` received a malformed frame (e.g. a missing UART stop bit).
+    ///
+    /// The menu reports the error, drops any partially received line up to the next line
+    /// terminator and keeps running.
+    Framing,
+
+    /// The `IoDevice` detected a parity error.
+    ///
+    /// The menu reports the error, drops any partially received line up to the next line
+    /// terminator and keeps running.
+    Parity,
+
+    /// A device specific error, identified by a code chosen by the `IoDevice`.
+    ///
+    /// The menu reports the error, drops any partially received line up to the next line
+    /// terminator and keeps running.
+    Other(u8),
 }
 
 /// Possible errors that the `Menu` might encounter while running.
-#[derive(Debug, defmt::Format, PartialEq)]
+#[derive(Debug, defmt::Format, PartialEq, Clone, Copy)]
 pub enum MenuError {
     /// A command was received that was not recognised.
     UnknownCommand,
@@ -36,9 +82,47 @@ pub enum MenuError {
     OutputBufferOverflow,
 
     /// `Menu` ran out of input buffer space while reading from its `IoDevice`.
+    /// The line that did not fit is dropped as a whole and never executed.
     ///
     /// Increasing the size of the menu's input buffer could prevent this.
     InputBufferOverflow,
+
+    /// No input was received within the idle timeout of the menu.
+    ///
+    /// Any partially entered line has been dropped when this error is reported.
+    IdleTimeout,
+
+    /// A `Command` failed with an error message of its own.
+    ///
+    /// The message is shown to the user and the menu keeps running.
+    Command(&'static str),
+
+    /// A command was received that is not enabled in the current state of the menu, see
+    /// `Command::is_enabled`.
+    CommandUnavailable,
+}
+
+/// Errors that can occur while registering commands with a `Menu`.
+#[derive(Debug, defmt::Format, PartialEq, Clone, Copy)]
+pub enum RegistrationError {
+    /// A command with an empty name was registered.
+    EmptyName,
+
+    /// The name of the command contains whitespace, which means it could never be entered.
+    InvalidName(&'static str),
+
+    /// The name of the command is reserved by the menu itself (i.e. it is the name of the
+    /// help command).
+    ReservedName(&'static str),
+
+    /// Another command with the same name or alias has already been registered, or a
+    /// command uses the same name or alias more than once.
+    DuplicateName(&'static str),
+
+    /// The dispatch table of the menu has no space left for this name or alias.
+    ///
+    /// The table needs one entry for the name and every alias of each command.
+    DispatchTableFull(&'static str),
 }
 
 impl From<IoDeviceError> for MenuError {
@@ -66,318 +150,2982 @@ pub trait IoDevice {
     ) -> impl Future<Output = Result<usize, IoDeviceError>>;
 }
 
+const PAGER_PROMPT: &str = "--More--";
+const PAGER_PROMPT_CLEAR: &str = "\r        \r";
+
+/// Keeps track of how many lines have been written by a command so that output can be
+/// paused with a `--More--` prompt once a page is full.
+struct Pager {
+    page_height: Option<usize>,
+    lines_written: usize,
+    aborted: bool,
+}
+
+impl Pager {
+    fn new(page_height: Option<usize>) -> Self {
+        Self {
+            page_height,
+            lines_written: 0,
+            aborted: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.lines_written = 0;
+        self.aborted = false;
+    }
+
+    async fn write<IO: IoDevice>(
+        &mut self,
+        io_device: &mut IO,
+        prompting: &Cell<bool>,
+        data: &[u8],
+    ) -> Result<(), IoDeviceError> {
+        if self.aborted {
+            return Ok(());
+        }
+
+        let Some(page_height) = self.page_height else {
+            return io_device.write_packet(data).await;
+        };
+
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            if self.lines_written >= page_height {
+                prompting.set(true);
+                let res = self.wait_for_user(io_device, page_height).await;
+                prompting.set(false);
+                res?;
+                if self.aborted {
+                    return Ok(());
+                }
+            }
+
+            // Only write up to the end of the current page
+            let mut chunk_len = remaining.len();
+            for (i, c) in remaining.iter().enumerate() {
+                if *c == b'\n' {
+                    self.lines_written += 1;
+                    if self.lines_written >= page_height {
+                        chunk_len = i + 1;
+                        break;
+                    }
+                }
+            }
+
+            io_device.write_packet(&remaining[..chunk_len]).await?;
+            remaining = &remaining[chunk_len..];
+        }
+
+        Ok(())
+    }
+
+    async fn wait_for_user<IO: IoDevice>(
+        &mut self,
+        io_device: &mut IO,
+        page_height: usize,
+    ) -> Result<(), IoDeviceError> {
+        io_device.write_packet(PAGER_PROMPT.as_bytes()).await?;
+
+        let mut key_buffer = [0; 16];
+        loop {
+            let n_bytes_read = match io_device.read_packet(&mut key_buffer).await {
+                Ok(n_bytes_read) => n_bytes_read,
+                Err(IoDeviceError::Timeout | IoDeviceError::WouldBlock) => {
+                    yield_now().await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Only the first key of a packet is considered, the rest is dropped
+            match key_buffer[..n_bytes_read].first() {
+                Some(b' ') => self.lines_written = 0,
+                Some(b'\n' | b'\r') => self.lines_written = page_height - 1,
+                Some(b'q') => self.aborted = true,
+                _ => continue,
+            }
+            break;
+        }
+
+        io_device.write_packet(PAGER_PROMPT_CLEAR.as_bytes()).await
+    }
+}
+
+/// A `Timer` enables a menu to end sessions that have been idle for too long. It could
+/// for instance be implemented using `embassy_time::Timer`.
+pub trait Timer {
+    /// Returns a future that completes once `duration_ms` milliseconds have passed.
+    fn delay_ms(&mut self, duration_ms: u32) -> impl Future<Output = ()>;
+}
+
+/// Completes once the user has been prompted by the pager for `timeout_ms` milliseconds
+/// without answering. It is meant to be selected together with the command that writes the
+/// output, which is polled on every wake-up, so changes of `prompting` need no waker of their
+/// own.
+async fn expire_prompt<T: Timer>(timer: &mut T, timeout_ms: u32, prompting: &Cell<bool>) {
+    loop {
+        poll_fn(|_| match prompting.get() {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        })
+        .await;
+
+        let answered = poll_fn(|_| match prompting.get() {
+            true => Poll::Pending,
+            false => Poll::Ready(()),
+        });
+        if let Either::First(()) = select(timer.delay_ms(timeout_ms), answered).await {
+            return;
+        }
+    }
+}
+
+/// Lets the executor run other tasks before the caller continues. Reads that fail with
+/// `Timeout` or `WouldBlock` are retried after yielding, since a device may report these right
+/// away, which would otherwise keep the menu spinning without ever polling its idle timeout.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// The `Timer` used by menus without an idle timeout, which never expires.
+struct NoTimer {}
+
+impl Timer for NoTimer {
+    async fn delay_ms(&mut self, _duration_ms: u32) {
+        core::future::pending().await
+    }
+}
+
+enum Either<A, B> {
+    First(A),
+    Second(B),
+}
+
+/// Polls whichever future it holds, which lets a function return one of two futures without
+/// the extra state that an `async` block awaiting either of them would need.
+impl<A: Future, B: Future<Output = A::Output>> Future for Either<A, B> {
+    type Output = A::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the held future is never moved out of the pinned `Either`
+        unsafe {
+            match self.get_unchecked_mut() {
+                Either::First(a) => Pin::new_unchecked(a).poll(cx),
+                Either::Second(b) => Pin::new_unchecked(b).poll(cx),
+            }
+        }
+    }
+}
+
+/// Waits for the first of two futures to complete and drops the other one.
+async fn select<A: Future, B: Future>(a: A, b: B) -> Either<A::Output, B::Output> {
+    let mut a = pin!(a);
+    let mut b = pin!(b);
+
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = a.as_mut().poll(cx) {
+            return Poll::Ready(Either::First(output));
+        }
+        if let Poll::Ready(output) = b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Second(output));
+        }
+        Poll::Pending
+    })
+    .await
+}
+
 /// An Output handle is provided to `Command` callbacks to enable them to write outputs.
 pub struct Output<'d, IO: IoDevice> {
     io_device: &'d mut IO,
     buffer: &'d mut [u8],
     buffer_idx: &'d mut usize,
+    pager: Pager,
+    /// Set while the pager waits for the user at its `--More--` prompt.
+    prompting: &'d Cell<bool>,
+    /// The privilege level of the current session, which decides the commands that can be
+    /// run and are listed by the help command.
+    privilege_level: u8,
+}
+
+impl<IO: IoDevice> Output<'_, IO> {
+    /// Writes directly to the menu's `IoDevice`.
+    pub async fn write(&mut self, s: &str) -> Result<(), IoDeviceError> {
+        self.pager
+            .write(self.io_device, self.prompting, s.as_bytes())
+            .await
+    }
+
+    /// Flushes the internal buffer to the menu's `IoDevice`.
+    /// You should probably not be calling this directly.
+    pub async fn flush_buffer(&mut self) -> Result<(), IoDeviceError> {
+        self.pager
+            .write(
+                self.io_device,
+                self.prompting,
+                &self.buffer[..*self.buffer_idx],
+            )
+            .await?;
+
+        *self.buffer_idx = 0;
+        Ok(())
+    }
+
+    /// Returns whether the user has aborted the output of the current command using the pager.
+    /// Any further output will be discarded, so long-running commands may want to stop early.
+    pub fn aborted(&self) -> bool {
+        self.pager.aborted
+    }
+}
+
+impl<IO: IoDevice> uWrite for Output<'_, IO> {
+    type Error = MenuError;
+
+    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
+        let bytes = s.as_bytes();
+
+        let start_idx = *self.buffer_idx;
+        if start_idx >= self.buffer.len() {
+            return Err(MenuError::OutputBufferOverflow);
+        }
+
+        let end_idx = start_idx + bytes.len();
+        if end_idx >= self.buffer.len() {
+            return Err(MenuError::OutputBufferOverflow);
+        }
+
+        self.buffer[start_idx..end_idx].clone_from_slice(bytes);
+        *self.buffer_idx = end_idx;
+        Ok(())
+    }
 }
 
-impl<IO: IoDevice> Output<'_, IO> {
-    /// Writes directly to the menu's `IoDevice`.
-    pub async fn write(&mut self, s: &str) -> Result<(), IoDeviceError> {
-        self.io_device.write_packet(s.as_bytes()).await
+/// Macro allows you to write formatted text using an `Output` handle.
+#[macro_export]
+macro_rules! outwriteln {
+    ($out:expr, $($tt:tt)*) => {{
+        match ufmt::uwriteln!($out, $($tt)*) {
+            Ok(_) => $out.flush_buffer().await.map_err(|e| MenuError::Io(e)),
+            e => e,
+        }
+    }}
+}
+
+/// Additional information about the circumstances under which an error occurred.
+pub struct ErrorContext<'a> {
+    /// The raw input line that was being processed when the error occurred. This is `None`
+    /// for errors that are not caused by a specific line, such as read errors.
+    pub line: Option<&'a [u8]>,
+
+    /// The name of the command that failed, if the error was caused by a command.
+    /// For `help <name>`, this is the name of the command that help was requested for.
+    pub command: Option<&'a str>,
+
+    /// For `MenuError::UnknownCommand`, this is the name of a visible command that is
+    /// spelled similarly to the unknown one (if there is any).
+    pub suggestion: Option<&'static str>,
+}
+
+/// An `ErrorRenderer` decides how errors that occur while the menu is running are presented
+/// to the user and whether the menu should keep running afterwards.
+pub trait ErrorRenderer<IO: IoDevice> {
+    /// Renders an error using the provided output handle. Returning `Ok` keeps the menu running
+    /// while returning an error stops it, in which case `Menu::run` returns that error.
+    /// A disconnected `IoDevice` stops the menu regardless of the result.
+    fn render(
+        &self,
+        error: MenuError,
+        context: &ErrorContext<'_>,
+        output: &mut Output<'_, IO>,
+    ) -> impl Future<Output = Result<(), MenuError>>;
+}
+
+/// The `ErrorRenderer` that is used unless another one is configured. It prints a short
+/// English message for every error and only stops the menu when the `IoDevice` disconnects
+/// or when the output buffer overflows.
+pub struct DefaultErrorRenderer {}
+
+impl<IO: IoDevice> ErrorRenderer<IO> for DefaultErrorRenderer {
+    async fn render(
+        &self,
+        error: MenuError,
+        context: &ErrorContext<'_>,
+        output: &mut Output<'_, IO>,
+    ) -> Result<(), MenuError> {
+        match error {
+            MenuError::Io(IoDeviceError::Disconnected) => Err(error),
+            MenuError::UnknownCommand => match (context.command, context.suggestion) {
+                (Some(cmd), Some(suggestion)) => outwriteln!(
+                    output,
+                    "Unknown command '{}'. Did you mean '{}'?",
+                    cmd,
+                    suggestion
+                ),
+                _ => outwriteln!(output, "Unknown command"),
+            },
+            MenuError::Io(IoDeviceError::BufferOverflow) => {
+                outwriteln!(output, "IO buffer overflow")
+            }
+            MenuError::Io(IoDeviceError::Timeout) => {
+                outwriteln!(output, "IO timeout")
+            }
+            MenuError::Io(IoDeviceError::WouldBlock) => Ok(()),
+            MenuError::Io(IoDeviceError::Framing) => {
+                outwriteln!(output, "IO framing error")
+            }
+            MenuError::Io(IoDeviceError::Parity) => {
+                outwriteln!(output, "IO parity error")
+            }
+            MenuError::Io(IoDeviceError::Other(code)) => {
+                outwriteln!(output, "IO error {}", code)
+            }
+            MenuError::Utf8 => {
+                outwriteln!(output, "Input UTF8 error")
+            }
+            MenuError::InputBufferOverflow => {
+                outwriteln!(output, "Input buffer overflowed & dumped")
+            }
+            MenuError::Command(message) => {
+                outwriteln!(output, "Error: {}", message)
+            }
+            MenuError::CommandUnavailable => {
+                outwriteln!(output, "Command not available in current mode")
+            }
+            MenuError::IdleTimeout => {
+                outwriteln!(output, "Session timed out")?;
+                Err(error)
+            }
+
+            // We need to abort when then output buffer is full since that
+            // condition prevents us from outputting an error message.
+            MenuError::OutputBufferOverflow => Err(error),
+        }
+    }
+}
+
+/// Describes the command that a `Hook` is called for.
+pub struct CommandContext<'a> {
+    /// The prefix of the module the command belongs to, if any.
+    pub prefix: Option<&'static str>,
+
+    /// The name of the command, even if it was run using one of its aliases.
+    pub name: &'static str,
+
+    /// The arguments that were passed to the command. They are always `None` for the login
+    /// command, since they contain the password.
+    pub args: Option<&'a str>,
+}
+
+/// A `Hook` is called before and after every command that is run by the menu, e.g. to log
+/// commands, to measure how long they take or to refuse to run them while the device is in a
+/// safe mode. This includes the help command as well as the login and logout commands, where
+/// entering the password after a `login` without arguments counts as running `login` again.
+/// Several hooks can be combined as a tuple `(A, B)`, in which case `A` is called before `B`
+/// and `B` is called before `A` afterwards.
+pub trait Hook<IO: IoDevice, S> {
+    /// Called before the command runs. Returning an error vetoes the command, in which case
+    /// the error is presented by the `ErrorRenderer` like any other error of the command.
+    fn before(
+        &mut self,
+        _command: &CommandContext<'_>,
+        _output: &mut Output<'_, IO>,
+        _state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> {
+        ready(Ok(()))
+    }
+
+    /// Called after the command ran, or after a hook vetoed it, with its result. The returned
+    /// result is the one that is passed on to the `ErrorRenderer`.
+    fn after(
+        &mut self,
+        _command: &CommandContext<'_>,
+        result: Result<(), MenuError>,
+        _output: &mut Output<'_, IO>,
+        _state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> {
+        ready(result)
+    }
+}
+
+/// The `Hook` that is used unless another one is added, which does nothing.
+struct NoHook {}
+
+impl<IO: IoDevice, S> Hook<IO, S> for NoHook {}
+
+impl<IO: IoDevice, S, A: Hook<IO, S>, B: Hook<IO, S>> Hook<IO, S> for (A, B) {
+    async fn before(
+        &mut self,
+        command: &CommandContext<'_>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> Result<(), MenuError> {
+        self.0.before(command, output, state).await?;
+        self.1.before(command, output, state).await
+    }
+
+    async fn after(
+        &mut self,
+        command: &CommandContext<'_>,
+        result: Result<(), MenuError>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> Result<(), MenuError> {
+        let result = self.1.after(command, result, output, state).await;
+        self.0.after(command, result, output, state).await
+    }
+}
+
+/// Static information about a registered command.
+#[derive(Clone, Copy)]
+struct CommandInfo {
+    prefix: Option<&'static str>,
+    name: &'static str,
+    help_string: &'static str,
+    long_help: Option<&'static str>,
+    aliases: &'static [&'static str],
+    category: Option<&'static str>,
+    hidden: bool,
+    required_level: u8,
+    /// Whether the command can currently be run, which is always the case for information that
+    /// does not take the state of the menu into account.
+    enabled: bool,
+}
+
+impl CommandInfo {
+    fn matches(&self, prefix: Option<&str>, cmd: &str) -> bool {
+        prefix == self.prefix && (cmd == self.name || self.aliases.contains(&cmd))
+    }
+
+    /// Returns whether a session with the given privilege level may see and run the command.
+    fn permitted(&self, privilege_level: u8) -> bool {
+        self.required_level <= privilege_level
+    }
+
+    /// Returns the number of characters of the name as it is entered, including any prefix.
+    fn name_width(&self) -> usize {
+        let prefix_width = self.prefix.map_or(0, |prefix| prefix.chars().count() + 1);
+        prefix_width + self.name.chars().count()
+    }
+
+    fn write_name<IO: IoDevice>(&self, output: &mut Output<'_, IO>) -> Result<(), MenuError> {
+        if let Some(prefix) = self.prefix {
+            ufmt::uwrite!(output, "{} ", prefix)?;
+        }
+        output.write_str(self.name)
+    }
+}
+
+trait Router<IO: IoDevice, S> {
+    /// Returns the future that executes the command with index `idx`. This deliberately is not
+    /// an `async fn`: the returned futures of all routers are nested `Either`s, so that their
+    /// size is bounded by the largest command future instead of growing with every router.
+    fn execute<'a>(
+        &'a mut self,
+        idx: usize,
+        args: Option<&'a str>,
+        output: &'a mut Output<'_, IO>,
+        state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a;
+
+    /// Returns the index of the command that has `name` as its name or one of its aliases and
+    /// that belongs to a module with the given `prefix`.
+    fn find(&self, prefix: Option<&str>, name: &str) -> Option<usize>;
+
+    /// Returns the number of commands that are handled by this router and the ones after it.
+    fn command_count(&self) -> usize;
+
+    /// Returns information about the command with index `idx`, where commands are numbered
+    /// in the order in which they were registered.
+    fn command_info(&self, idx: usize) -> Option<CommandInfo>;
+
+    /// Like `command_info`, but describes whether the command is hidden and enabled in `state`.
+    fn command_info_in(&self, idx: usize, state: &S) -> Option<CommandInfo>;
+}
+
+/// Iterates over the commands handled by `router` in the order in which they were registered.
+fn command_infos<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+) -> impl Iterator<Item = CommandInfo> + '_ {
+    (0..router.command_count()).filter_map(|idx| router.command_info(idx))
+}
+
+/// Like `command_infos`, but describes whether the commands are hidden and enabled in `state`.
+fn command_infos_in<'a, IO: IoDevice, S>(
+    router: &'a impl Router<IO, S>,
+    state: &'a S,
+) -> impl Iterator<Item = CommandInfo> + 'a {
+    (0..router.command_count()).filter_map(move |idx| router.command_info_in(idx, state))
+}
+
+/// Finds the command that is run by entering `line`, which may start with the prefix of a module.
+/// Returns its index together with the remaining arguments.
+fn resolve_command<'l, IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    dispatcher: &impl Dispatcher,
+    cmd: &'l str,
+    args: Option<&'l str>,
+) -> Option<(usize, Option<&'l str>)> {
+    if let Some(idx) = dispatcher.find(router, None, cmd) {
+        return Some((idx, args));
+    }
+
+    let (name, args) = split_command(args?);
+    dispatcher
+        .find(router, Some(cmd), name)
+        .map(|idx| (idx, args))
+}
+
+/// Finds the command that is run by entering `line`, such as `status` or `imu read`.
+fn find_command<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    line: &str,
+) -> Option<CommandInfo> {
+    let (cmd, args) = split_command(line);
+    resolve_command(router, &ChainDispatcher {}, cmd, args)
+        .filter(|(_, args)| args.is_none())
+        .and_then(|(idx, _)| router.command_info_in(idx, state))
+}
+
+/// Commands for a menu are specified by providing structs that implement the Command trait.
+/// This allows the menu to understand how to implement the command.
+/// Commands that need to be registered more than once with a different configuration can
+/// implement `CommandInstance` instead.
+pub trait Command<IO: IoDevice, S> {
+    /// Returns the name of this command i.e. what needs to be entered to run it.
+    fn name() -> &'static str;
+
+    /// Executes the logic of the command. It is provided with an output handle to print outputs
+    /// and a state handle to access menu state (as passed in when the menu was created).
+    fn execute(
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>>;
+
+    /// Returns the help string that will be printed for this command.
+    fn help_string() -> &'static str;
+
+    /// Returns a detailed description of this command, such as its usage and some examples.
+    /// It is printed by `help <name>`, together with the help string and any aliases.
+    fn long_help() -> Option<&'static str> {
+        None
+    }
+
+    /// Returns alternative names that can also be entered to run this command.
+    fn aliases() -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Returns the category of this command. The help command lists commands grouped by their
+    /// category, and `help <category>` lists only the commands of that category.
+    fn category() -> Option<&'static str> {
+        None
+    }
+
+    /// Returns whether this command should be hidden from the default help output.
+    /// Hidden commands only appear when using `help <name>` or the hidden flag of the help
+    /// command (`help --hidden` by default).
+    fn hidden() -> bool {
+        false
+    }
+
+    /// Returns the privilege level that is needed to run this command when the menu has a
+    /// login, see `Menu::with_login`. Commands that need a higher level than the current one
+    /// behave as if they did not exist.
+    fn required_level() -> u8 {
+        0
+    }
+
+    /// Returns whether this command should be hidden from the default help output in the
+    /// current state of the menu, e.g. to only show calibration commands in factory mode.
+    /// Defaults to `hidden()`.
+    fn is_hidden(_state: &S) -> bool {
+        Self::hidden()
+    }
+
+    /// Returns whether this command can be run in the current state of the menu. Running a
+    /// disabled command results in `MenuError::CommandUnavailable`.
+    fn is_enabled(_state: &S) -> bool {
+        true
+    }
+}
+
+/// A set of commands, such as the commands of a driver crate, that can be registered at once
+/// as a module using `Menu::with_module`. It can be derived for an enum of which every variant
+/// is a command, see `derive(CommandSet)`.
+pub trait CommandSet<IO: IoDevice, S> {
+    /// Registers all commands of this set with `menu`, which collects the commands of the menu
+    /// or sub-menu that the set is registered with.
+    fn register(menu: impl SubMenu<IO, S>) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Returns a prefix that needs to be entered before the name of every command of this set,
+    /// e.g. `imu` to run a command named `read` by entering `imu read`. `help imu` lists the
+    /// commands with this prefix.
+    fn prefix() -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the category of the commands of this set that do not specify one themselves.
+    fn category() -> Option<&'static str> {
+        None
+    }
+}
+
+/// The module of commands that are not registered as part of a `CommandSet`.
+struct NoModule {}
+
+impl<IO: IoDevice, S> CommandSet<IO, S> for NoModule {
+    fn register(menu: impl SubMenu<IO, S>) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        Ok(menu)
+    }
+}
+
+/// Like `Command`, but implemented by command values rather than types. This allows several
+/// instances of the same command with a different configuration to be registered using
+/// `Menu::with_command_instance`, e.g. one command per bus of a device.
+pub trait CommandInstance<IO: IoDevice, S> {
+    /// Returns the name of this command i.e. what needs to be entered to run it.
+    fn name(&self) -> &'static str;
+
+    /// Executes the logic of the command. It is provided with an output handle to print outputs
+    /// and a state handle to access menu state (as passed in when the menu was created).
+    fn execute(
+        &mut self,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>>;
+
+    /// Returns the help string that will be printed for this command.
+    fn help_string(&self) -> &'static str;
+
+    /// Returns a detailed description of this command, such as its usage and some examples.
+    /// It is printed by `help <name>`, together with the help string and any aliases.
+    fn long_help(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns alternative names that can also be entered to run this command.
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Returns the category of this command. The help command lists commands grouped by their
+    /// category, and `help <category>` lists only the commands of that category.
+    fn category(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns whether this command should be hidden from the default help output.
+    /// Hidden commands only appear when using `help <name>` or the hidden flag of the help
+    /// command (`help --hidden` by default).
+    fn hidden(&self) -> bool {
+        false
+    }
+
+    /// Returns the privilege level that is needed to run this command when the menu has a
+    /// login, see `Menu::with_login`. Commands that need a higher level than the current one
+    /// behave as if they did not exist.
+    fn required_level(&self) -> u8 {
+        0
+    }
+
+    /// Returns whether this command should be hidden from the default help output in the
+    /// current state of the menu, e.g. to only show calibration commands in factory mode.
+    /// Defaults to `hidden()`.
+    fn is_hidden(&self, _state: &S) -> bool {
+        self.hidden()
+    }
+
+    /// Returns whether this command can be run in the current state of the menu. Running a
+    /// disabled command results in `MenuError::CommandUnavailable`.
+    fn is_enabled(&self, _state: &S) -> bool {
+        true
+    }
+}
+
+/// A condition on the state of the menu that decides whether an `FnCommand` is hidden or
+/// enabled, see `FnCommand::with_hidden_when` and `FnCommand::with_enabled_when`. It is
+/// implemented by closures taking the state, and by `()` for commands without a condition.
+pub trait StatePredicate<S> {
+    /// Returns the result of the condition, or `None` if there is no condition.
+    fn check(&self, state: &S) -> Option<bool>;
+}
+
+impl<S> StatePredicate<S> for () {
+    fn check(&self, _state: &S) -> Option<bool> {
+        None
+    }
+}
+
+impl<S, P: Fn(&S) -> bool> StatePredicate<S> for P {
+    fn check(&self, state: &S) -> Option<bool> {
+        Some(self(state))
+    }
+}
+
+/// A command that runs an async closure, which saves defining a type for small commands.
+/// It can be registered using `Menu::with_fn`, or using `Menu::with_command_instance` after
+/// configuring it further, e.g. to hide it from the help output.
+pub struct FnCommand<F, H = (), E = ()> {
+    name: &'static str,
+    help_string: &'static str,
+    long_help: Option<&'static str>,
+    aliases: &'static [&'static str],
+    category: Option<&'static str>,
+    hidden: bool,
+    required_level: u8,
+    hidden_when: H,
+    enabled_when: E,
+    f: F,
+}
+
+impl<F> FnCommand<F> {
+    /// Creates a command named `name` that runs `f` as an `async` closure, which is provided
+    /// with the arguments, the output handle and the menu state.
+    pub fn new<IO: IoDevice, S>(name: &'static str, help_string: &'static str, f: F) -> Self
+    where
+        F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>,
+    {
+        Self {
+            name,
+            help_string,
+            long_help: None,
+            aliases: &[],
+            category: None,
+            hidden: false,
+            required_level: 0,
+            hidden_when: (),
+            enabled_when: (),
+            f,
+        }
+    }
+}
+
+impl<F, H, E> FnCommand<F, H, E> {
+    /// Sets the detailed description that is printed by `help <name>`.
+    pub fn with_long_help(mut self, long_help: &'static str) -> Self {
+        self.long_help = Some(long_help);
+        self
+    }
+
+    /// Sets alternative names that can also be entered to run this command.
+    pub fn with_aliases(mut self, aliases: &'static [&'static str]) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Sets the category under which the help command lists this command.
+    pub fn with_category(mut self, category: &'static str) -> Self {
+        self.category = Some(category);
+        self
+    }
+
+    /// Sets whether this command should be hidden from the default help output.
+    pub fn with_hidden(mut self, hidden: bool) -> Self {
+        self.hidden = hidden;
+        self
+    }
+
+    /// Sets the privilege level that is needed to run this command, see `Menu::with_login`.
+    pub fn with_required_level(mut self, level: u8) -> Self {
+        self.required_level = level;
+        self
+    }
+
+    /// Hides this command from the default help output whenever `predicate` returns `true`
+    /// for the state of the menu, see `CommandInstance::is_hidden`. This takes precedence
+    /// over `with_hidden`.
+    pub fn with_hidden_when<P>(self, predicate: P) -> FnCommand<F, P, E> {
+        FnCommand {
+            name: self.name,
+            help_string: self.help_string,
+            long_help: self.long_help,
+            aliases: self.aliases,
+            category: self.category,
+            hidden: self.hidden,
+            required_level: self.required_level,
+            hidden_when: predicate,
+            enabled_when: self.enabled_when,
+            f: self.f,
+        }
+    }
+
+    /// Only allows running this command while `predicate` returns `true` for the state of the
+    /// menu, see `CommandInstance::is_enabled`.
+    pub fn with_enabled_when<P>(self, predicate: P) -> FnCommand<F, H, P> {
+        FnCommand {
+            name: self.name,
+            help_string: self.help_string,
+            long_help: self.long_help,
+            aliases: self.aliases,
+            category: self.category,
+            hidden: self.hidden,
+            required_level: self.required_level,
+            hidden_when: self.hidden_when,
+            enabled_when: predicate,
+            f: self.f,
+        }
+    }
+}
+
+impl<IO: IoDevice, S, F, H, E> CommandInstance<IO, S> for FnCommand<F, H, E>
+where
+    F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>,
+    H: StatePredicate<S>,
+    E: StatePredicate<S>,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn execute(
+        &mut self,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> {
+        (self.f)(args, output, state)
+    }
+
+    fn help_string(&self) -> &'static str {
+        self.help_string
+    }
+
+    fn long_help(&self) -> Option<&'static str> {
+        self.long_help
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        self.aliases
+    }
+
+    fn category(&self) -> Option<&'static str> {
+        self.category
+    }
+
+    fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    fn required_level(&self) -> u8 {
+        self.required_level
+    }
+
+    fn is_hidden(&self, state: &S) -> bool {
+        self.hidden_when.check(state).unwrap_or(self.hidden)
+    }
+
+    fn is_enabled(&self, state: &S) -> bool {
+        self.enabled_when.check(state).unwrap_or(true)
+    }
+}
+
+/// Makes a `Command` usable wherever a `CommandInstance` is expected.
+struct StaticCommand<CMD> {
+    _cmd_marker: PhantomData<CMD>,
+}
+
+impl<CMD> StaticCommand<CMD> {
+    fn new() -> Self {
+        Self {
+            _cmd_marker: PhantomData,
+        }
+    }
+}
+
+impl<IO: IoDevice, S, CMD: Command<IO, S>> CommandInstance<IO, S> for StaticCommand<CMD> {
+    fn name(&self) -> &'static str {
+        CMD::name()
+    }
+
+    fn execute(
+        &mut self,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> {
+        CMD::execute(args, output, state)
+    }
+
+    fn help_string(&self) -> &'static str {
+        CMD::help_string()
+    }
+
+    fn long_help(&self) -> Option<&'static str> {
+        CMD::long_help()
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        CMD::aliases()
+    }
+
+    fn category(&self) -> Option<&'static str> {
+        CMD::category()
+    }
+
+    fn hidden(&self) -> bool {
+        CMD::hidden()
+    }
+
+    fn required_level(&self) -> u8 {
+        CMD::required_level()
+    }
+
+    fn is_hidden(&self, state: &S) -> bool {
+        CMD::is_hidden(state)
+    }
+
+    fn is_enabled(&self, state: &S) -> bool {
+        CMD::is_enabled(state)
+    }
+}
+
+/// Holds a command together with the module it was registered in, which provides its prefix
+/// and default category without taking up any space.
+struct CommandHolder<IO: IoDevice, S, CMD: CommandInstance<IO, S>, M: CommandSet<IO, S>> {
+    cmd: CMD,
+    _io_marker: PhantomData<IO>,
+    _state_marker: PhantomData<S>,
+    _module_marker: PhantomData<M>,
+}
+
+impl<IO: IoDevice, S, CMD: CommandInstance<IO, S>, M: CommandSet<IO, S>>
+    CommandHolder<IO, S, CMD, M>
+{
+    fn matches(&self, prefix: Option<&str>, cmd: &str) -> bool {
+        self.info().matches(prefix, cmd)
+    }
+
+    fn info(&self) -> CommandInfo {
+        CommandInfo {
+            prefix: M::prefix(),
+            name: self.cmd.name(),
+            help_string: self.cmd.help_string(),
+            long_help: self.cmd.long_help(),
+            aliases: self.cmd.aliases(),
+            category: self.cmd.category().or(M::category()),
+            hidden: self.cmd.hidden(),
+            required_level: self.cmd.required_level(),
+            enabled: true,
+        }
+    }
+
+    fn info_in(&self, state: &S) -> CommandInfo {
+        CommandInfo {
+            hidden: self.cmd.is_hidden(state),
+            enabled: self.cmd.is_enabled(state),
+            ..self.info()
+        }
+    }
+}
+
+impl<IO: IoDevice, S, CMD: CommandInstance<IO, S>, M: CommandSet<IO, S>>
+    CommandHolder<IO, S, CMD, M>
+{
+    fn new(cmd: CMD) -> Self {
+        Self {
+            cmd,
+            _io_marker: PhantomData,
+            _state_marker: PhantomData,
+            _module_marker: PhantomData,
+        }
+    }
+}
+
+const MAX_SUGGESTION_DISTANCE: usize = 2;
+const MAX_SUGGESTION_LEN: usize = 32;
+
+/// Computes the optimal string alignment distance between `a` and `b`, which is the Levenshtein
+/// distance extended by transpositions of adjacent characters. To avoid allocations, `None` is
+/// returned if either input is longer than `MAX_SUGGESTION_LEN`.
+fn edit_distance(a: &[u8], b: &[u8]) -> Option<usize> {
+    if a.len() > MAX_SUGGESTION_LEN || b.len() > MAX_SUGGESTION_LEN {
+        return None;
+    }
+
+    let mut prev_prev_row = [0; MAX_SUGGESTION_LEN + 1];
+    let mut prev_row = [0; MAX_SUGGESTION_LEN + 1];
+    let mut row = [0; MAX_SUGGESTION_LEN + 1];
+
+    for (j, cell) in prev_row.iter_mut().enumerate().take(b.len() + 1) {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        row[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (prev_row[j] + 1)
+                .min(row[j - 1] + 1)
+                .min(prev_row[j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(prev_prev_row[j - 2] + 1);
+            }
+        }
+
+        prev_prev_row = prev_row;
+        prev_row = row;
+    }
+
+    Some(prev_row[b.len()])
+}
+
+/// Returns the visible command whose name is closest to `cmd`, as long as the names are
+/// similar enough for `cmd` to plausibly be a typo.
+fn closest_command<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    cmd: &str,
+    privilege_level: u8,
+) -> Option<&'static str> {
+    let mut closest: Option<(&'static str, usize)> = None;
+
+    let candidates = command_infos_in(router, state)
+        .filter(|info| !info.hidden && info.prefix.is_none() && info.permitted(privilege_level));
+    for info in candidates {
+        // An exact match means that the command itself failed to find what was entered after
+        // it, e.g. for sub-menus, so it would not be a helpful suggestion
+        let distance = edit_distance(cmd.as_bytes(), info.name.as_bytes())
+            .filter(|d| *d > 0 && *d <= MAX_SUGGESTION_DISTANCE && d * 2 < info.name.len());
+
+        if let Some(distance) = distance
+            && closest.is_none_or(|(_, d)| distance < d)
+        {
+            closest = Some((info.name, distance));
+        }
+    }
+
+    closest.map(|(name, _)| name)
+}
+
+/// The index of the help command, which is the first command of every menu.
+const HELP_IDX: usize = 0;
+
+/// Routes to the help command and to the registered commands, which are numbered after it.
+/// Running the help command needs access to all other commands, which is why
+/// `CommandTree::execute` runs it rather than this router.
+struct MenuRouter<L> {
+    help: Option<HelpCommand>,
+    commands: L,
+}
+
+impl<L> MenuRouter<L> {
+    fn with_command<IO: IoDevice, S, R: Router<IO, S>>(
+        self,
+        router: R,
+    ) -> MenuRouter<L::Appended<R>>
+    where
+        L: CommandList<IO, S>,
+    {
+        MenuRouter {
+            help: self.help,
+            commands: self.commands.append(router),
+        }
+    }
+}
+
+impl<IO: IoDevice, S, L: CommandList<IO, S>> Router<IO, S> for MenuRouter<L> {
+    fn execute<'a>(
+        &'a mut self,
+        idx: usize,
+        args: Option<&'a str>,
+        output: &'a mut Output<'_, IO>,
+        state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a {
+        if idx == HELP_IDX {
+            Either::First(ready(Err(MenuError::UnknownCommand)))
+        } else {
+            Either::Second(self.commands.execute(idx - 1, args, output, state))
+        }
+    }
+
+    fn find(&self, prefix: Option<&str>, name: &str) -> Option<usize> {
+        if self
+            .help
+            .is_some_and(|help| help.info().matches(prefix, name))
+        {
+            Some(HELP_IDX)
+        } else {
+            self.commands.find(prefix, name).map(|idx| idx + 1)
+        }
+    }
+
+    fn command_count(&self) -> usize {
+        self.commands.command_count() + 1
+    }
+
+    fn command_info(&self, idx: usize) -> Option<CommandInfo> {
+        if idx == HELP_IDX {
+            self.help.map(|help| help.info())
+        } else {
+            self.commands.command_info(idx - 1)
+        }
+    }
+
+    fn command_info_in(&self, idx: usize, state: &S) -> Option<CommandInfo> {
+        if idx == HELP_IDX {
+            self.help.map(|help| help.info())
+        } else {
+            self.commands.command_info_in(idx - 1, state)
+        }
+    }
+}
+
+/// Routes to a single command, which has index 0.
+impl<IO: IoDevice, S, CMD: CommandInstance<IO, S>, M: CommandSet<IO, S>> Router<IO, S>
+    for CommandHolder<IO, S, CMD, M>
+{
+    fn execute<'a>(
+        &'a mut self,
+        _idx: usize,
+        args: Option<&'a str>,
+        output: &'a mut Output<'_, IO>,
+        state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a {
+        self.cmd.execute(args, output, state)
+    }
+
+    fn find(&self, prefix: Option<&str>, name: &str) -> Option<usize> {
+        self.matches(prefix, name).then_some(0)
+    }
+
+    fn command_count(&self) -> usize {
+        1
+    }
+
+    fn command_info(&self, idx: usize) -> Option<CommandInfo> {
+        (idx == 0).then(|| self.info())
+    }
+
+    fn command_info_in(&self, idx: usize, state: &S) -> Option<CommandInfo> {
+        (idx == 0).then(|| self.info_in(state))
+    }
+}
+
+/// Routes to the commands of `first`, followed by those of `second`. Which of them handles a
+/// command is decided by a single comparison of its index, so running a command of a balanced
+/// tree of these routers takes a number of comparisons that is logarithmic in its size.
+struct SplitRouter<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<IO: IoDevice, S, A: Router<IO, S>, B: Router<IO, S>> Router<IO, S> for SplitRouter<A, B> {
+    fn execute<'a>(
+        &'a mut self,
+        idx: usize,
+        args: Option<&'a str>,
+        output: &'a mut Output<'_, IO>,
+        state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a {
+        // The number of commands is a constant once inlined, except for mounted sub-menus
+        let split = self.first.command_count();
+        if idx < split {
+            Either::First(self.first.execute(idx, args, output, state))
+        } else {
+            Either::Second(self.second.execute(idx - split, args, output, state))
+        }
+    }
+
+    fn find(&self, prefix: Option<&str>, name: &str) -> Option<usize> {
+        self.first.find(prefix, name).or_else(|| {
+            let split = self.first.command_count();
+            self.second.find(prefix, name).map(|idx| idx + split)
+        })
+    }
+
+    fn command_count(&self) -> usize {
+        self.first.command_count() + self.second.command_count()
+    }
+
+    fn command_info(&self, idx: usize) -> Option<CommandInfo> {
+        let split = self.first.command_count();
+        if idx < split {
+            self.first.command_info(idx)
+        } else {
+            self.second.command_info(idx - split)
+        }
+    }
+
+    fn command_info_in(&self, idx: usize, state: &S) -> Option<CommandInfo> {
+        let split = self.first.command_count();
+        if idx < split {
+            self.first.command_info_in(idx, state)
+        } else {
+            self.second.command_info_in(idx - split, state)
+        }
+    }
+}
+
+/// The registered commands of a menu, in the order in which they were registered. They are
+/// held by balanced trees of `SplitRouter`s whose sizes are distinct powers of two, like the
+/// digits of a binary number: a list is either `NoCommands`, a `Vacant` digit or a
+/// `SplitRouter` of the rest of the list and the tree of its lowest digit. Appending a
+/// command works like incrementing the number, merging trees of the same size, which keeps
+/// both the number of trees and their depth logarithmic in the number of commands.
+trait CommandList<IO: IoDevice, S>: Router<IO, S> {
+    type Appended<R: Router<IO, S>>: CommandList<IO, S>;
+
+    /// Appends `router`, which has as many commands as the trees of the lowest digit.
+    fn append<R: Router<IO, S>>(self, router: R) -> Self::Appended<R>;
+}
+
+/// The end of a `CommandList`.
+struct NoCommands {}
+
+impl<IO: IoDevice, S> Router<IO, S> for NoCommands {
+    fn execute<'a>(
+        &'a mut self,
+        _idx: usize,
+        _args: Option<&'a str>,
+        _output: &'a mut Output<'_, IO>,
+        _state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a {
+        ready(Err(MenuError::UnknownCommand))
+    }
+
+    fn find(&self, _prefix: Option<&str>, _name: &str) -> Option<usize> {
+        None
+    }
+
+    fn command_count(&self) -> usize {
+        0
+    }
+
+    fn command_info(&self, _idx: usize) -> Option<CommandInfo> {
+        None
+    }
+
+    fn command_info_in(&self, _idx: usize, _state: &S) -> Option<CommandInfo> {
+        None
+    }
+}
+
+impl<IO: IoDevice, S> CommandList<IO, S> for NoCommands {
+    type Appended<R: Router<IO, S>> = SplitRouter<NoCommands, R>;
+
+    fn append<R: Router<IO, S>>(self, router: R) -> Self::Appended<R> {
+        SplitRouter {
+            first: self,
+            second: router,
+        }
+    }
+}
+
+/// A digit of a `CommandList` that holds no tree.
+struct Vacant<Rest> {
+    rest: Rest,
+}
+
+impl<IO: IoDevice, S, Rest: Router<IO, S>> Router<IO, S> for Vacant<Rest> {
+    fn execute<'a>(
+        &'a mut self,
+        idx: usize,
+        args: Option<&'a str>,
+        output: &'a mut Output<'_, IO>,
+        state: &'a mut S,
+    ) -> impl Future<Output = Result<(), MenuError>> + 'a {
+        self.rest.execute(idx, args, output, state)
+    }
+
+    fn find(&self, prefix: Option<&str>, name: &str) -> Option<usize> {
+        self.rest.find(prefix, name)
+    }
+
+    fn command_count(&self) -> usize {
+        self.rest.command_count()
+    }
+
+    fn command_info(&self, idx: usize) -> Option<CommandInfo> {
+        self.rest.command_info(idx)
+    }
+
+    fn command_info_in(&self, idx: usize, state: &S) -> Option<CommandInfo> {
+        self.rest.command_info_in(idx, state)
+    }
+}
+
+impl<IO: IoDevice, S, Rest: CommandList<IO, S>> CommandList<IO, S> for Vacant<Rest> {
+    type Appended<R: Router<IO, S>> = SplitRouter<Rest, R>;
+
+    fn append<R: Router<IO, S>>(self, router: R) -> Self::Appended<R> {
+        SplitRouter {
+            first: self.rest,
+            second: router,
+        }
+    }
+}
+
+impl<IO: IoDevice, S, Rest: CommandList<IO, S>, T: Router<IO, S>> CommandList<IO, S>
+    for SplitRouter<Rest, T>
+{
+    type Appended<R: Router<IO, S>> = Vacant<Rest::Appended<SplitRouter<T, R>>>;
+
+    fn append<R: Router<IO, S>>(self, router: R) -> Self::Appended<R> {
+        // The lowest digit overflows, so its tree is merged with `router` and carried over
+        Vacant {
+            rest: self.first.append(SplitRouter {
+                first: self.second,
+                second: router,
+            }),
+        }
+    }
+}
+
+/// Finds the command that has to be executed for a line of input.
+trait Dispatcher {
+    /// Adds the name and aliases of the command with index `idx`.
+    fn insert(&mut self, info: &CommandInfo, idx: usize) -> Result<(), RegistrationError>;
+
+    /// Removes the name and aliases of the command with index `idx`.
+    fn remove(&mut self, idx: usize);
+
+    /// Returns the index of the command that has `name` as its name or one of its aliases and
+    /// that belongs to a module with the given `prefix`.
+    fn find<IO: IoDevice, S>(
+        &self,
+        router: &impl Router<IO, S>,
+        prefix: Option<&str>,
+        name: &str,
+    ) -> Option<usize>;
+}
+
+/// Finds commands by comparing the name of every registered command in turn.
+struct ChainDispatcher {}
+
+impl Dispatcher for ChainDispatcher {
+    fn insert(&mut self, _info: &CommandInfo, _idx: usize) -> Result<(), RegistrationError> {
+        Ok(())
+    }
+
+    fn remove(&mut self, _idx: usize) {}
+
+    fn find<IO: IoDevice, S>(
+        &self,
+        router: &impl Router<IO, S>,
+        prefix: Option<&str>,
+        name: &str,
+    ) -> Option<usize> {
+        router.find(prefix, name)
+    }
+}
+
+/// An entry of the dispatch table of a menu, which maps a name or alias to its command. See
+/// `Menu::with_dispatch_table`.
+#[derive(Clone, Copy)]
+pub struct DispatchEntry {
+    prefix: Option<&'static str>,
+    name: &'static str,
+    idx: usize,
+}
+
+impl DispatchEntry {
+    /// An unused entry, for initializing the storage of a dispatch table.
+    pub const EMPTY: Self = Self {
+        prefix: None,
+        name: "",
+        idx: 0,
+    };
+}
+
+/// Finds commands using a binary search through a table of names and aliases that is kept
+/// sorted by prefix and name. The entries are borrowed like the input and output buffers, so
+/// that the table does not add to the size of the menu.
+struct DispatchTable<'t> {
+    entries: &'t mut [DispatchEntry],
+    len: usize,
+}
+
+impl<'t> DispatchTable<'t> {
+    fn new(entries: &'t mut [DispatchEntry]) -> Self {
+        Self { entries, len: 0 }
+    }
+
+    fn insert_name(
+        &mut self,
+        prefix: Option<&'static str>,
+        name: &'static str,
+        idx: usize,
+    ) -> Result<(), RegistrationError> {
+        if self.len == self.entries.len() {
+            return Err(RegistrationError::DispatchTableFull(name));
+        }
+
+        let insert_idx = self.entries[..self.len]
+            .partition_point(|entry| (entry.prefix, entry.name) < (prefix, name));
+        self.entries
+            .copy_within(insert_idx..self.len, insert_idx + 1);
+        self.entries[insert_idx] = DispatchEntry { prefix, name, idx };
+        self.len += 1;
+        Ok(())
+    }
+}
+
+impl Dispatcher for DispatchTable<'_> {
+    fn insert(&mut self, info: &CommandInfo, idx: usize) -> Result<(), RegistrationError> {
+        self.insert_name(info.prefix, info.name, idx)?;
+        for alias in info.aliases {
+            self.insert_name(info.prefix, alias, idx)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, idx: usize) {
+        let mut len = 0;
+        for entry_idx in 0..self.len {
+            if self.entries[entry_idx].idx != idx {
+                self.entries[len] = self.entries[entry_idx];
+                len += 1;
+            }
+        }
+        self.len = len;
+    }
+
+    fn find<IO: IoDevice, S>(
+        &self,
+        _router: &impl Router<IO, S>,
+        prefix: Option<&str>,
+        name: &str,
+    ) -> Option<usize> {
+        let entries = &self.entries[..self.len];
+        entries
+            .binary_search_by(|entry| (entry.prefix, entry.name).cmp(&(prefix, name)))
+            .ok()
+            .map(|entry_idx| entries[entry_idx].idx)
+    }
+}
+
+/// Checks whether `name` can be used to register a command of a module with the given `prefix`
+/// with a menu whose commands are handled by `router`.
+fn validate_name<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    prefix: Option<&'static str>,
+    name: &'static str,
+) -> Result<(), RegistrationError> {
+    if name.is_empty() {
+        return Err(RegistrationError::EmptyName);
+    }
+    if name.contains(char::is_whitespace) {
+        return Err(RegistrationError::InvalidName(name));
+    }
+    match router.find(prefix, name) {
+        Some(HELP_IDX) => return Err(RegistrationError::ReservedName(name)),
+        Some(_) => return Err(RegistrationError::DuplicateName(name)),
+        None => {}
+    }
+    // Commands without a prefix must not be mistaken for the prefix of a module
+    if prefix.is_none() && command_infos(router).any(|info| info.prefix == Some(name)) {
+        return Err(RegistrationError::DuplicateName(name));
+    }
+    Ok(())
+}
+
+/// Checks the name and aliases of a command like `validate_name`, and that they are distinct.
+fn validate_names<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    prefix: Option<&'static str>,
+    name: &'static str,
+    aliases: &'static [&'static str],
+) -> Result<(), RegistrationError> {
+    validate_name(router, prefix, name)?;
+    for (i, alias) in aliases.iter().enumerate() {
+        validate_name(router, prefix, alias)?;
+
+        if *alias == name || aliases[..i].contains(alias) {
+            return Err(RegistrationError::DuplicateName(alias));
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether `prefix` can be used as the prefix of a module, which must not be mistaken
+/// for a command without a prefix.
+fn validate_prefix<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    prefix: &'static str,
+) -> Result<(), RegistrationError> {
+    if prefix.is_empty() {
+        return Err(RegistrationError::EmptyName);
+    }
+    if prefix.contains(char::is_whitespace) {
+        return Err(RegistrationError::InvalidName(prefix));
+    }
+    match router.find(None, prefix) {
+        Some(HELP_IDX) => Err(RegistrationError::ReservedName(prefix)),
+        Some(_) => Err(RegistrationError::DuplicateName(prefix)),
+        None => Ok(()),
+    }
+}
+
+/// You probably don't want to implement this trait yourself! This trait is used to make
+/// the internal structure of the Menu opaque to the user which is useful for implementing
+/// the builder pattern in the way it has been done here.
+///
+/// What this means is that when you build a Menu, all you know is that you will end up with
+/// "something" that has the interface specified by this trait.
+pub trait Menu<IO: IoDevice, S> {
+    /// Registers a new command with the Menu. An error is returned if the name or one of the
+    /// aliases of the command is empty, contains whitespace, is reserved or has already been
+    /// registered.
+    fn with_command<CMD: Command<IO, S>>(self) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Registers a command value with the Menu, which allows registering several instances of
+    /// the same command with a different configuration. The same checks as in `with_command`
+    /// are applied to its name and aliases.
+    fn with_command_instance<CMD: CommandInstance<IO, S>>(
+        self,
+        cmd: CMD,
+    ) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Registers an `async` closure as a command, e.g.
+    /// `menu.with_fn("ping", "Replies", async |_args, output, _state| outwriteln!(output, "pong"))`.
+    /// The closure is provided with the arguments, the output handle and the menu state. The
+    /// same checks as in `with_command` are applied to its name. See `FnCommand` for
+    /// registering closures that need aliases, a category or should be hidden.
+    fn with_fn<F>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        f: F,
+    ) -> Result<impl Menu<IO, S>, RegistrationError>
+    where
+        F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>;
+
+    /// Registers all commands of a `CommandSet` with the Menu as a module, which applies the
+    /// prefix and default category of the set to its commands. An error is returned if the
+    /// prefix is invalid or already used as the name of a command, or if any of the commands
+    /// fails the checks of `with_command`.
+    fn with_module<M: CommandSet<IO, S>>(self) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Mounts a sub-menu (see `make_submenu`) under `name`, so that entering `name <command>`
+    /// runs one of its commands and entering just `name` or `name help` shows its own help.
+    /// The commands of the sub-menu share the output of this menu and run on the part of the
+    /// state that `projection` selects, e.g. `|state: &mut State| &mut state.diag`. The same
+    /// checks as in `with_command` are applied to `name`. The sub-menu needs the lowest
+    /// privilege level of its commands, so it is only listed once one of them can be run, and
+    /// it is hidden if all of its commands are. Its commands can still be hidden or disabled
+    /// depending on the state.
+    fn with_submenu<SubS, SM: SubMenu<IO, SubS>, P: FnMut(&mut S) -> &mut SubS>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        submenu: SM,
+        projection: P,
+    ) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Replaces the `DefaultErrorRenderer` with a custom `ErrorRenderer`, which controls how
+    /// errors are presented and whether the menu keeps running after an error.
+    fn with_error_renderer<ER: ErrorRenderer<IO>>(self, renderer: ER) -> impl Menu<IO, S>;
+
+    /// Adds a `Hook` that is called before and after every command of the menu. Hooks that
+    /// were added before are called first before a command and last after it.
+    fn with_hook<H: Hook<IO, S>>(self, hook: H) -> impl Menu<IO, S>;
+
+    /// Protects commands that have a `required_level` above 0 with a login. Entering
+    /// `login <password>`, or `login` followed by the password on the next line, raises the
+    /// privilege level of the session to the one that `verifier` grants for the password, and
+    /// `logout` or an idle timeout lowers it to 0 again. Commands that need a higher level
+    /// behave as if they did not exist and are left out of the help output. After
+    /// `max_attempts` failed logins in a row, logging in is refused until the menu is started
+    /// again (0 allows unlimited attempts). An error is returned if `login` or `logout` is
+    /// already used by a command. Sub-menus use the login of the menu they are mounted in.
+    fn with_login<V: PasswordVerifier>(
+        self,
+        verifier: V,
+        max_attempts: u8,
+    ) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Makes the menu look up commands in a table that is kept sorted by name, so that finding
+    /// the command of a line takes a binary search instead of comparing its name with every
+    /// registered command in turn. The table is stored in `entries`, e.g.
+    /// `&mut [DispatchEntry::EMPTY; 70]`, which needs one entry for the name and every alias
+    /// of each command, including the help command. It is filled with the commands registered
+    /// so far and those registered later, and an error is returned when a name does not fit.
+    ///
+    /// Command names are returned by trait methods that cannot be evaluated at compile time,
+    /// which is why the table is built while registering commands rather than placed in
+    /// flash. Use `cargo bench` to find out whether the table pays off for a given menu.
+    fn with_dispatch_table(
+        self,
+        entries: &mut [DispatchEntry],
+    ) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Replaces the built-in help command, e.g. to rename it or change how it formats its output.
+    /// An error is returned if its name is invalid or already used by a registered command.
+    fn with_help(self, help: HelpCommand) -> Result<impl Menu<IO, S>, RegistrationError>;
+
+    /// Removes the built-in help command, which also frees up its name for other commands.
+    fn without_help(self) -> impl Menu<IO, S>;
+
+    /// Enables a pager that pauses command output with a `--More--` prompt after every
+    /// `page_height` lines. The user can then press space to show the next page, enter to
+    /// show the next line or `q` to discard the remaining output of the command.
+    /// A `page_height` of 0 disables the pager again.
+    fn with_pager(self, page_height: usize) -> impl Menu<IO, S>;
+
+    /// Ends the session with `MenuError::IdleTimeout` when no input is received for
+    /// `timeout_ms` milliseconds, using `timer` to keep track of time. This includes sessions
+    /// left at the `--More--` prompt of the pager or at the password prompt of the login,
+    /// in which case the interrupted command is dropped. The `ErrorRenderer` decides whether
+    /// the menu stops or resets the session and keeps running.
+    fn with_idle_timeout<T: Timer>(self, timer: T, timeout_ms: u32) -> impl Menu<IO, S>;
+
+    /// Runs the Menu until it encounters an unrecoverable error or its `IODevice` disconnects.
+    fn run(self) -> impl Future<Output = Result<(), MenuError>>;
+}
+
+/// The builder of a sub-menu, see `make_submenu`. It offers the methods of `Menu` that
+/// register commands or change how they are found and run, while the settings that only apply
+/// to a menu with its own `IoDevice` (such as the login, pager or idle timeout) are left out.
+/// It is also used to register the commands of a `CommandSet`.
+pub trait SubMenu<IO: IoDevice, S>: sealed::IntoCommandTree<IO, S> {
+    /// Registers a new command, see `Menu::with_command`.
+    fn with_command<CMD: Command<IO, S>>(self) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Registers a command value, see `Menu::with_command_instance`.
+    fn with_command_instance<CMD: CommandInstance<IO, S>>(
+        self,
+        cmd: CMD,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Registers an `async` closure as a command, see `Menu::with_fn`.
+    fn with_fn<F>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        f: F,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError>
+    where
+        F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>;
+
+    /// Registers all commands of a `CommandSet` as a module, see `Menu::with_module`.
+    fn with_module<M: CommandSet<IO, S>>(self) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Mounts another sub-menu under `name`, see `Menu::with_submenu`.
+    fn with_submenu<SubS, SM: SubMenu<IO, SubS>, P: FnMut(&mut S) -> &mut SubS>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        submenu: SM,
+        projection: P,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Adds a `Hook` that is called before and after every command, see `Menu::with_hook`.
+    fn with_hook<H: Hook<IO, S>>(self, hook: H) -> impl SubMenu<IO, S>;
+
+    /// Looks up commands in a sorted table, see `Menu::with_dispatch_table`.
+    fn with_dispatch_table(
+        self,
+        entries: &mut [DispatchEntry],
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Replaces the built-in help command, see `Menu::with_help`.
+    fn with_help(self, help: HelpCommand) -> Result<impl SubMenu<IO, S>, RegistrationError>;
+
+    /// Removes the built-in help command, see `Menu::without_help`.
+    fn without_help(self) -> impl SubMenu<IO, S>;
+}
+
+use sealed::IntoCommandTree;
+
+mod sealed {
+    use super::*;
+
+    /// Hands the commands of a `SubMenu` over to the menu it is mounted in. This is not part of
+    /// `SubMenu` itself, so that it can neither be called nor implemented outside of this crate.
+    // The trait cannot be named outside of this crate, so the private types it uses stay hidden
+    #[allow(private_bounds, private_interfaces)]
+    pub trait IntoCommandTree<IO: IoDevice, S> {
+        type Commands: CommandList<IO, S>;
+        type Dispatcher: Dispatcher;
+        type Module: CommandSet<IO, S>;
+        type Hook: Hook<IO, S>;
+
+        fn into_command_tree(
+            self,
+        ) -> CommandTree<IO, S, Self::Commands, Self::Dispatcher, Self::Module, Self::Hook>;
+    }
+}
+
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    ER: ErrorRenderer<IO>,
+    T: Timer,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    V: PasswordVerifier,
+> Menu<IO, S> for MenuImpl<'_, IO, S, L, D, ER, T, Module, H, V>
+{
+    fn with_command<CMD: Command<IO, S>>(self) -> Result<impl Menu<IO, S>, RegistrationError> {
+        self.with_command_instance(StaticCommand::<CMD>::new())
+    }
+
+    fn with_command_instance<CMD: CommandInstance<IO, S>>(
+        self,
+        cmd: CMD,
+    ) -> Result<impl Menu<IO, S>, RegistrationError> {
+        if Module::prefix().is_none() {
+            check_login_names(&self.login, cmd.name())?;
+            for alias in cmd.aliases() {
+                check_login_names(&self.login, alias)?;
+            }
+        }
+
+        Ok(MenuImpl {
+            commands: self.commands.with_command_instance(cmd)?,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: self.login,
+        })
+    }
+
+    fn with_fn<F>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        f: F,
+    ) -> Result<impl Menu<IO, S>, RegistrationError>
+    where
+        F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>,
+    {
+        self.with_command_instance(FnCommand::new(name, help_string, f))
+    }
+
+    fn with_module<M: CommandSet<IO, S>>(self) -> Result<impl Menu<IO, S>, RegistrationError> {
+        self.commands.validate_module::<M>()?;
+        if let Some(prefix) = M::prefix() {
+            check_login_names(&self.login, prefix)?;
+        }
+
+        // Commands registered after the module belong to the current module again
+        let submenu = SubMenuImpl {
+            commands: self.commands.within_module::<M>(),
+        };
+        let commands = M::register(submenu)?
+            .into_command_tree()
+            .within_module::<Module>();
+        if M::prefix().is_none() {
+            check_login_commands(&self.login, &commands.router)?;
+        }
+
+        Ok(MenuImpl {
+            commands,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: self.login,
+        })
+    }
+
+    fn with_submenu<SubS, SM: SubMenu<IO, SubS>, P: FnMut(&mut S) -> &mut SubS>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        submenu: SM,
+        projection: P,
+    ) -> Result<impl Menu<IO, S>, RegistrationError> {
+        let commands = submenu.into_command_tree();
+        self.with_command_instance(commands.into_mounted(name, help_string, projection))
+    }
+
+    fn with_error_renderer<NewER: ErrorRenderer<IO>>(self, renderer: NewER) -> impl Menu<IO, S> {
+        MenuImpl {
+            commands: self.commands,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: self.login,
+        }
+    }
+
+    fn with_hook<NewH: Hook<IO, S>>(self, hook: NewH) -> impl Menu<IO, S> {
+        MenuImpl {
+            commands: self.commands.with_hook(hook),
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: self.login,
+        }
+    }
+
+    fn with_dispatch_table(
+        self,
+        entries: &mut [DispatchEntry],
+    ) -> Result<impl Menu<IO, S>, RegistrationError> {
+        Ok(MenuImpl {
+            commands: self.commands.with_dispatch_table(entries)?,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: self.login,
+        })
+    }
+
+    fn with_help(mut self, help: HelpCommand) -> Result<impl Menu<IO, S>, RegistrationError> {
+        check_login_names(&self.login, help.name)?;
+        for alias in help.aliases {
+            check_login_names(&self.login, alias)?;
+        }
+        self.commands.set_help(help)?;
+        Ok(self)
+    }
+
+    fn without_help(mut self) -> impl Menu<IO, S> {
+        self.commands.remove_help();
+        self
+    }
+
+    fn with_login<NewV: PasswordVerifier>(
+        self,
+        verifier: NewV,
+        max_attempts: u8,
+    ) -> Result<impl Menu<IO, S>, RegistrationError> {
+        for name in [LOGIN_NAME, LOGOUT_NAME] {
+            validate_name(&self.commands.router, None, name)?;
+        }
+
+        Ok(MenuImpl {
+            commands: self.commands,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: self.idle_timer,
+            idle_timeout_ms: self.idle_timeout_ms,
+            login: Some(Login::new(verifier, max_attempts)),
+        })
+    }
+
+    fn with_pager(mut self, page_height: usize) -> impl Menu<IO, S> {
+        self.page_height = (page_height > 0).then_some(page_height);
+        self
+    }
+
+    fn with_idle_timeout<NewT: Timer>(self, timer: NewT, timeout_ms: u32) -> impl Menu<IO, S> {
+        MenuImpl {
+            commands: self.commands,
+            input_buffer: self.input_buffer,
+            input_buffer_idx: self.input_buffer_idx,
+            discarding_input: self.discarding_input,
+            output_buffer: self.output_buffer,
+            output_buffer_idx: self.output_buffer_idx,
+            io_device: self.io_device,
+            state: self.state,
+            page_height: self.page_height,
+            error_renderer: self.error_renderer,
+            idle_timer: timer,
+            idle_timeout_ms: timeout_ms,
+            login: self.login,
+        }
+    }
+
+    // An `async move` block keeps a single copy of the menu in the future, whereas an
+    // `async fn` would hold on to both its argument and the moved menu
+    #[allow(clippy::manual_async_fn)]
+    fn run(mut self) -> impl Future<Output = Result<(), MenuError>> {
+        async move {
+            loop {
+                match self.read_input().await {
+                    Ok(_) => {}
+                    Err(MenuError::Io(IoDeviceError::Disconnected)) => return Ok(()),
+                    other => return other,
+                }
+            }
+        }
+    }
+}
+
+struct MenuImpl<
+    'd,
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    ER: ErrorRenderer<IO>,
+    T: Timer,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    V: PasswordVerifier,
+> {
+    commands: CommandTree<IO, S, L, D, Module, H>,
+    input_buffer: &'d mut [u8],
+    input_buffer_idx: usize,
+    discarding_input: bool,
+    output_buffer: &'d mut [u8],
+    output_buffer_idx: usize,
+    io_device: &'d mut IO,
+    state: &'d mut S,
+    page_height: Option<usize>,
+    error_renderer: ER,
+    idle_timer: T,
+    idle_timeout_ms: u32,
+    login: Option<Login<V>>,
+}
+
+/// The registered commands of a menu or sub-menu, together with the means to find them.
+struct CommandTree<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+> {
+    router: MenuRouter<L>,
+    dispatcher: D,
+    hook: H,
+    _io_marker: PhantomData<IO>,
+    _state_marker: PhantomData<S>,
+    /// The module that commands are registered in, see `Menu::with_module`.
+    _module_marker: PhantomData<Module>,
+}
+
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+> CommandTree<IO, S, L, D, Module, H>
+{
+    // The tree is spelled out since its commands cannot be hidden behind `impl Menu` here
+    #[allow(clippy::type_complexity)]
+    fn with_command_instance<CMD: CommandInstance<IO, S>>(
+        mut self,
+        cmd: CMD,
+    ) -> Result<CommandTree<IO, S, impl CommandList<IO, S>, D, Module, H>, RegistrationError> {
+        let cmd = CommandHolder::<IO, S, CMD, Module>::new(cmd);
+        let prefix = Module::prefix();
+        let name = cmd.cmd.name();
+        let aliases = cmd.cmd.aliases();
+
+        validate_names(&self.router, prefix, name, aliases)?;
+
+        self.dispatcher
+            .insert(&cmd.info(), self.router.command_count())?;
+
+        Ok(CommandTree {
+            router: self.router.with_command(cmd),
+            dispatcher: self.dispatcher,
+            hook: self.hook,
+            _io_marker: PhantomData,
+            _state_marker: PhantomData,
+            _module_marker: PhantomData,
+        })
+    }
+
+    fn validate_module<M: CommandSet<IO, S>>(&self) -> Result<(), RegistrationError> {
+        match M::prefix() {
+            Some(prefix) => validate_prefix(&self.router, prefix),
+            None => Ok(()),
+        }
+    }
+
+    fn within_module<M: CommandSet<IO, S>>(self) -> CommandTree<IO, S, L, D, M, H> {
+        CommandTree {
+            router: self.router,
+            dispatcher: self.dispatcher,
+            hook: self.hook,
+            _io_marker: PhantomData,
+            _state_marker: PhantomData,
+            _module_marker: PhantomData,
+        }
+    }
+
+    fn with_dispatch_table(
+        self,
+        entries: &mut [DispatchEntry],
+    ) -> Result<CommandTree<IO, S, L, DispatchTable<'_>, Module, H>, RegistrationError> {
+        let mut table = DispatchTable::new(entries);
+        for idx in 0..self.router.command_count() {
+            if let Some(info) = self.router.command_info(idx) {
+                table.insert(&info, idx)?;
+            }
+        }
+
+        Ok(CommandTree {
+            router: self.router,
+            dispatcher: table,
+            hook: self.hook,
+            _io_marker: PhantomData,
+            _state_marker: PhantomData,
+            _module_marker: PhantomData,
+        })
+    }
+
+    /// Replaces the help command. Its names are only checked against the other commands, so
+    /// it may reuse the names of the help command it replaces.
+    fn set_help(&mut self, help: HelpCommand) -> Result<(), RegistrationError> {
+        self.remove_help();
+
+        let info = help.info();
+        validate_names(&self.router, None, info.name, info.aliases)?;
+        self.dispatcher.insert(&info, HELP_IDX)?;
+        self.router.help = Some(help);
+        Ok(())
+    }
+
+    fn remove_help(&mut self) {
+        self.router.help = None;
+        self.dispatcher.remove(HELP_IDX);
+    }
+
+    fn into_mounted<Parent, P: FnMut(&mut Parent) -> &mut S>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        projection: P,
+    ) -> MountedMenu<IO, S, L, D, Module, H, P> {
+        MountedMenu {
+            name,
+            help_string,
+            commands: self,
+            projection,
+        }
+    }
+
+    /// Executes the command that `cmd` refers to.
+    async fn execute(
+        &mut self,
+        cmd: &str,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut S,
+    ) -> Result<(), MenuError> {
+        let Some((idx, args)) = resolve_command(&self.router, &self.dispatcher, cmd, args) else {
+            return Err(MenuError::UnknownCommand);
+        };
+        // Commands that need a higher privilege level are not revealed
+        let Some(info) = self
+            .router
+            .command_info_in(idx, state)
+            .filter(|info| info.permitted(output.privilege_level))
+        else {
+            return Err(MenuError::UnknownCommand);
+        };
+        if !info.enabled {
+            return Err(MenuError::CommandUnavailable);
+        }
+
+        let command = CommandContext {
+            prefix: info.prefix,
+            name: info.name,
+            args,
+        };
+        let result = match self.hook.before(&command, output, state).await {
+            Err(e) => Err(e),
+            Ok(()) if idx == HELP_IDX => match &self.router.help {
+                Some(help) => execute_help(&self.router, state, help, args, output).await,
+                None => Err(MenuError::UnknownCommand),
+            },
+            Ok(()) => self.router.execute(idx, args, output, state).await,
+        };
+        self.hook.after(&command, result, output, state).await
+    }
+
+    fn with_hook<NewH: Hook<IO, S>>(
+        self,
+        hook: NewH,
+    ) -> CommandTree<IO, S, L, D, Module, (H, NewH)> {
+        CommandTree {
+            router: self.router,
+            dispatcher: self.dispatcher,
+            hook: (self.hook, hook),
+            _io_marker: PhantomData,
+            _state_marker: PhantomData,
+            _module_marker: PhantomData,
+        }
+    }
+}
+
+/// A menu without an `IoDevice` of its own, see `make_submenu`.
+struct SubMenuImpl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+> {
+    commands: CommandTree<IO, S, L, D, Module, H>,
+}
+
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+> SubMenu<IO, S> for SubMenuImpl<IO, S, L, D, Module, H>
+{
+    fn with_command<CMD: Command<IO, S>>(self) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        self.with_command_instance(StaticCommand::<CMD>::new())
+    }
+
+    fn with_command_instance<CMD: CommandInstance<IO, S>>(
+        self,
+        cmd: CMD,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        Ok(SubMenuImpl {
+            commands: self.commands.with_command_instance(cmd)?,
+        })
+    }
+
+    fn with_fn<F>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        f: F,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError>
+    where
+        F: AsyncFnMut(Option<&str>, &mut Output<'_, IO>, &mut S) -> Result<(), MenuError>,
+    {
+        self.with_command_instance(FnCommand::new(name, help_string, f))
+    }
+
+    fn with_module<M: CommandSet<IO, S>>(self) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        self.commands.validate_module::<M>()?;
+
+        // Commands registered after the module belong to the current module again
+        let submenu = SubMenuImpl {
+            commands: self.commands.within_module::<M>(),
+        };
+        Ok(SubMenuImpl {
+            commands: M::register(submenu)?
+                .into_command_tree()
+                .within_module::<Module>(),
+        })
+    }
+
+    fn with_submenu<SubS, SM: SubMenu<IO, SubS>, P: FnMut(&mut S) -> &mut SubS>(
+        self,
+        name: &'static str,
+        help_string: &'static str,
+        submenu: SM,
+        projection: P,
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        let commands = submenu.into_command_tree();
+        self.with_command_instance(commands.into_mounted(name, help_string, projection))
+    }
+
+    fn with_hook<NewH: Hook<IO, S>>(self, hook: NewH) -> impl SubMenu<IO, S> {
+        SubMenuImpl {
+            commands: self.commands.with_hook(hook),
+        }
+    }
+
+    fn with_dispatch_table(
+        self,
+        entries: &mut [DispatchEntry],
+    ) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        Ok(SubMenuImpl {
+            commands: self.commands.with_dispatch_table(entries)?,
+        })
+    }
+
+    fn with_help(mut self, help: HelpCommand) -> Result<impl SubMenu<IO, S>, RegistrationError> {
+        self.commands.set_help(help)?;
+        Ok(self)
+    }
+
+    fn without_help(mut self) -> impl SubMenu<IO, S> {
+        self.commands.remove_help();
+        self
+    }
+}
+
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+> sealed::IntoCommandTree<IO, S> for SubMenuImpl<IO, S, L, D, Module, H>
+{
+    type Commands = L;
+    type Dispatcher = D;
+    type Module = Module;
+    type Hook = H;
+
+    fn into_command_tree(self) -> CommandTree<IO, S, L, D, Module, H> {
+        self.commands
+    }
+}
+
+/// A sub-menu that has been mounted under a name in another menu, which runs the commands of
+/// the sub-menu on the part of the state of the other menu that `projection` selects.
+struct MountedMenu<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    P,
+> {
+    name: &'static str,
+    help_string: &'static str,
+    commands: CommandTree<IO, S, L, D, Module, H>,
+    projection: P,
+}
+
+impl<
+    IO: IoDevice,
+    Parent,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    P: FnMut(&mut Parent) -> &mut S,
+> CommandInstance<IO, Parent> for MountedMenu<IO, S, L, D, Module, H, P>
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn execute(
+        &mut self,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        state: &mut Parent,
+    ) -> Result<(), MenuError> {
+        let (cmd, args) = match (args, &self.commands.router.help) {
+            (Some(line), _) => split_command(line),
+            // Entering just the name of the sub-menu shows its help
+            (None, Some(help)) => (help.name, None),
+            (None, None) => return Err(MenuError::UnknownCommand),
+        };
+
+        let state = (self.projection)(state);
+        self.commands.execute(cmd, args, output, state).await
     }
 
-    /// Flushes the internal buffer to the menu's `IoDevice`.
-    /// You should probably not be calling this directly.
-    pub async fn flush_buffer(&mut self) -> Result<(), IoDeviceError> {
-        self.io_device
-            .write_packet(&self.buffer[..*self.buffer_idx])
-            .await?;
+    fn help_string(&self) -> &'static str {
+        self.help_string
+    }
 
-        *self.buffer_idx = 0;
-        Ok(())
+    fn hidden(&self) -> bool {
+        let mut commands = self.commands();
+        commands.next().is_some_and(|first| first.hidden) && commands.all(|info| info.hidden)
     }
-}
 
-impl<IO: IoDevice> uWrite for Output<'_, IO> {
-    type Error = MenuError;
+    fn required_level(&self) -> u8 {
+        self.commands()
+            .map(|info| info.required_level)
+            .min()
+            .unwrap_or(0)
+    }
+}
 
-    fn write_str(&mut self, s: &str) -> Result<(), Self::Error> {
-        let bytes = s.as_bytes();
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    P,
+> MountedMenu<IO, S, L, D, Module, H, P>
+{
+    /// Iterates over the commands of the sub-menu other than its help command.
+    fn commands(&self) -> impl Iterator<Item = CommandInfo> + '_ {
+        let router = &self.commands.router;
+        (HELP_IDX + 1..router.command_count()).filter_map(|idx| router.command_info(idx))
+    }
+}
 
-        let start_idx = *self.buffer_idx;
-        if start_idx >= self.buffer.len() {
-            return Err(MenuError::OutputBufferOverflow);
-        }
+/// Verifies the passwords that are entered using the login command of a menu, see
+/// `Menu::with_login`.
+pub trait PasswordVerifier {
+    /// Returns the privilege level that `password` grants, or `None` if it is not valid.
+    fn verify(&mut self, password: &str) -> impl Future<Output = Option<u8>>;
+}
 
-        let end_idx = start_idx + bytes.len();
-        if end_idx >= self.buffer.len() {
-            return Err(MenuError::OutputBufferOverflow);
-        }
+/// The verifier of menus without a login, which never gets to verify anything.
+struct NoVerifier {}
 
-        self.buffer[start_idx..end_idx].clone_from_slice(bytes);
-        *self.buffer_idx = end_idx;
-        Ok(())
+impl PasswordVerifier for NoVerifier {
+    async fn verify(&mut self, _password: &str) -> Option<u8> {
+        None
     }
 }
 
-/// Macro allows you to write formatted text using an `Output` handle.
-#[macro_export]
-macro_rules! outwriteln {
-    ($out:expr, $($tt:tt)*) => {{
-        match ufmt::uwriteln!($out, $($tt)*) {
-            Ok(_) => $out.flush_buffer().await.map_err(|e| MenuError::Io(e)),
-            e => e,
-        }
-    }}
+const LOGIN_NAME: &str = "login";
+const LOGOUT_NAME: &str = "logout";
+
+/// The session of a menu with a login, see `Menu::with_login`.
+struct Login<V: PasswordVerifier> {
+    verifier: V,
+    max_attempts: u8,
+    failed_attempts: u8,
+    /// Whether the next line is the password for a `login` without arguments.
+    awaiting_password: bool,
+    privilege_level: u8,
 }
 
-trait Router<IO: IoDevice, S> {
-    async fn execute_or_forward(
-        &self,
-        cmd: &str,
-        args: Option<&str>,
-        output: &mut Output<IO>,
-        state: &mut S,
-    ) -> Result<(), MenuError>;
+impl<V: PasswordVerifier> Login<V> {
+    fn new(verifier: V, max_attempts: u8) -> Self {
+        Self {
+            verifier,
+            max_attempts,
+            failed_attempts: 0,
+            awaiting_password: false,
+            privilege_level: 0,
+        }
+    }
 
-    async fn print_help(&self, output: &mut Output<IO>, show_hidden: bool)
-    -> Result<(), MenuError>;
-}
+    fn reserves(name: &str) -> bool {
+        Self::command(name).is_some()
+    }
 
-/// Commands for a menu are specified by providing structs that implement the Command trait.
-/// This allows the menu to understand how to implement the command.
-pub trait Command<IO: IoDevice, S> {
-    /// Returns the name of this command i.e. what needs to be entered to run it.
-    fn name() -> &'static str;
+    /// Returns the name of the login or logout command if `cmd` is one of them.
+    fn command(cmd: &str) -> Option<&'static str> {
+        [LOGIN_NAME, LOGOUT_NAME]
+            .into_iter()
+            .find(|name| *name == cmd)
+    }
 
-    /// Executes the logic of the command. It is provided with an output handle to print outputs
-    /// and a state handle to access menu state (as passed in when the menu was created).
-    fn execute(
+    /// Executes the login or logout command `name` between the hooks of the menu. The hooks
+    /// are not shown the arguments, since those contain the password when logging in.
+    async fn execute<IO: IoDevice, S>(
+        &mut self,
+        name: &'static str,
         args: Option<&str>,
+        hook: &mut impl Hook<IO, S>,
         output: &mut Output<'_, IO>,
         state: &mut S,
-    ) -> impl Future<Output = Result<(), MenuError>>;
-
-    /// Returns the help string that will be printed for this command.
-    fn help_string() -> &'static str;
-
-    /// Returns whether this command should be hidden from the default help output.
-    /// Hidden commands only appear when using `help --hidden`.
-    fn hidden() -> bool {
-        false
+    ) -> Result<(), MenuError> {
+        let command = CommandContext {
+            prefix: None,
+            name,
+            args: None,
+        };
+        let result = match hook.before(&command, output, state).await {
+            Ok(()) => self.execute_command(name, args, output).await,
+            Err(e) => {
+                // A vetoed login must not leave the next line to be taken as the password
+                self.awaiting_password = false;
+                Err(e)
+            }
+        };
+        hook.after(&command, result, output, state).await
     }
-}
-
-struct CommandHolder<IO: IoDevice, S, CMD: Command<IO, S>> {
-    _cmd_marker: PhantomData<CMD>,
-    _io_marker: PhantomData<IO>,
-    _state_marker: PhantomData<S>,
-}
 
-impl<IO: IoDevice, S, CMD: Command<IO, S>> CommandHolder<IO, S, CMD> {
-    async fn try_execute(
-        &self,
-        cmd: &str,
+    async fn execute_command<IO: IoDevice>(
+        &mut self,
+        name: &'static str,
         args: Option<&str>,
         output: &mut Output<'_, IO>,
-        state: &mut S,
-    ) -> Result<bool, MenuError> {
-        if cmd == CMD::name() {
-            CMD::execute(args, output, state).await?;
-            Ok(true)
-        } else {
-            Ok(false)
+    ) -> Result<(), MenuError> {
+        if name == LOGOUT_NAME {
+            self.logout();
+            return outwriteln!(output, "Logged out");
+        }
+
+        match args {
+            Some(password) => self.verify(password, output).await,
+            None => {
+                self.awaiting_password = true;
+                output.write("Password: ").await.map_err(MenuError::from)
+            }
         }
     }
 
-    async fn print_help(
-        &self,
+    async fn verify<IO: IoDevice>(
+        &mut self,
+        password: &str,
         output: &mut Output<'_, IO>,
-        show_hidden: bool,
     ) -> Result<(), MenuError> {
-        if show_hidden || !CMD::hidden() {
-            outwriteln!(output, "> {}: {}", CMD::name(), CMD::help_string())
-        } else {
-            Ok(())
+        self.awaiting_password = false;
+        if self.max_attempts > 0 && self.failed_attempts >= self.max_attempts {
+            return Err(MenuError::Command(
+                "Login locked after too many failed attempts",
+            ));
         }
-    }
-}
 
-impl<IO: IoDevice, S, CMD: Command<IO, S>> CommandHolder<IO, S, CMD> {
-    fn new() -> Self {
-        Self {
-            _cmd_marker: PhantomData,
-            _io_marker: PhantomData,
-            _state_marker: PhantomData,
+        match self.verifier.verify(password).await {
+            Some(level) => {
+                self.failed_attempts = 0;
+                self.privilege_level = level;
+                outwriteln!(output, "Logged in")
+            }
+            None => {
+                self.failed_attempts = self.failed_attempts.saturating_add(1);
+                Err(MenuError::Command("Wrong password"))
+            }
         }
     }
+
+    fn logout(&mut self) {
+        self.awaiting_password = false;
+        self.privilege_level = 0;
+    }
 }
 
-struct FinalRouter {}
+/// Returns the privilege level of the current session, where menus without a login allow
+/// running every command.
+fn privilege_level<V: PasswordVerifier>(login: &Option<Login<V>>) -> u8 {
+    login
+        .as_ref()
+        .map_or(u8::MAX, |login| login.privilege_level)
+}
 
-impl<IO: IoDevice, S> Router<IO, S> for FinalRouter {
-    async fn execute_or_forward(
-        &self,
-        _cmd: &str,
-        _args: Option<&str>,
-        _output: &mut Output<'_, IO>,
-        _state: &mut S,
-    ) -> Result<(), MenuError> {
-        Err(MenuError::UnknownCommand)
+/// Returns an error if a command without a prefix in `router` uses a name that is reserved by
+/// the login of a menu.
+fn check_login_commands<V: PasswordVerifier, IO: IoDevice, S>(
+    login: &Option<Login<V>>,
+    router: &impl Router<IO, S>,
+) -> Result<(), RegistrationError> {
+    if login.is_none() {
+        return Ok(());
     }
+    for name in [LOGIN_NAME, LOGOUT_NAME] {
+        if router.find(None, name).is_some() {
+            return Err(RegistrationError::DuplicateName(name));
+        }
+    }
+    Ok(())
+}
 
-    async fn print_help(
-        &self,
-        _output: &mut Output<'_, IO>,
-        _show_hidden: bool,
-    ) -> Result<(), MenuError> {
-        Ok(())
+/// Returns an error if `name` is reserved by the login of a menu.
+fn check_login_names<V: PasswordVerifier>(
+    login: &Option<Login<V>>,
+    name: &'static str,
+) -> Result<(), RegistrationError> {
+    if login.is_some() && Login::<V>::reserves(name) {
+        return Err(RegistrationError::ReservedName(name));
     }
+    Ok(())
 }
 
-struct NormalRouter<IO: IoDevice, S, NextRouter: Router<IO, S>, CMD: Command<IO, S>> {
-    cmd: CommandHolder<IO, S, CMD>,
-    next_router: NextRouter,
+/// The order in which the built-in help command lists commands.
+#[derive(Debug, defmt::Format, PartialEq, Clone, Copy)]
+pub enum HelpOrder {
+    /// Commands are listed in the order in which they were registered.
+    Registration,
+
+    /// Commands are listed alphabetically by name.
+    Alphabetical,
 }
 
-impl<IO: IoDevice, S, NextRouter: Router<IO, S>, CMD: Command<IO, S>> Router<IO, S>
-    for NormalRouter<IO, S, NextRouter, CMD>
-{
-    async fn execute_or_forward(
-        &self,
-        cmd: &str,
-        args: Option<&str>,
-        output: &mut Output<'_, IO>,
-        state: &mut S,
-    ) -> Result<(), MenuError> {
-        if self.cmd.try_execute(cmd, args, output, state).await? {
-            Ok(())
-        } else {
-            self.next_router
-                .execute_or_forward(cmd, args, output, state)
-                .await
+/// The built-in help command, which lists the registered commands. Every menu starts out with
+/// `HelpCommand::default()`, which can be replaced using `Menu::with_help` to rename or re-theme
+/// it, or removed entirely using `Menu::without_help`.
+///
+/// It is the first command of every menu and behaves like any other: it can have aliases and a
+/// category, it is subject to the required privilege level and hooks of the menu see it run.
+#[derive(Debug, defmt::Format, PartialEq, Clone, Copy)]
+pub struct HelpCommand {
+    /// The name that needs to be entered to run the help command.
+    pub name: &'static str,
+
+    /// The help string that is printed for the help command itself.
+    pub help_string: &'static str,
+
+    /// Alternative names that run the help command as well.
+    pub aliases: &'static [&'static str],
+
+    /// The category under which the help command is listed, see `Command::category`.
+    pub category: Option<&'static str>,
+
+    /// Whether the help command is hidden from its own output, which is the default.
+    pub hidden: bool,
+
+    /// The privilege level that is needed to see and run the help command, see
+    /// `Command::required_level`.
+    pub required_level: u8,
+
+    /// The header that is printed above the list of commands. Nothing is printed if it is empty.
+    pub header: &'static str,
+
+    /// The prefix that is printed in front of every command name.
+    pub prefix: &'static str,
+
+    /// The text that is printed in front of the name of every category.
+    pub category_prefix: &'static str,
+
+    /// The text that is printed after the name of every category.
+    pub category_suffix: &'static str,
+
+    /// The label in front of the aliases that `help <name>` prints.
+    pub aliases_label: &'static str,
+
+    /// The argument that makes the help command list hidden commands as well. `None` prevents
+    /// hidden commands from ever being listed, although `help <name>` still describes them.
+    pub hidden_flag: Option<&'static str>,
+
+    /// The order in which commands are listed.
+    pub order: HelpOrder,
+
+    /// Help strings are wrapped onto multiple lines so that no line exceeds this many
+    /// characters (unless a single word does not fit). `None` disables wrapping.
+    pub wrap_width: Option<usize>,
+}
+
+impl Default for HelpCommand {
+    fn default() -> Self {
+        Self {
+            name: "help",
+            help_string: "Lists the available commands",
+            aliases: &[],
+            category: None,
+            hidden: true,
+            required_level: 0,
+            header: "AVAILABLE COMMANDS:\n",
+            prefix: "> ",
+            category_prefix: "",
+            category_suffix: ":",
+            aliases_label: "Aliases: ",
+            hidden_flag: Some("--hidden"),
+            order: HelpOrder::Registration,
+            wrap_width: None,
+        }
+    }
+}
+
+impl HelpCommand {
+    fn info(&self) -> CommandInfo {
+        CommandInfo {
+            prefix: None,
+            name: self.name,
+            help_string: self.help_string,
+            long_help: None,
+            aliases: self.aliases,
+            category: self.category,
+            hidden: self.hidden,
+            required_level: self.required_level,
+            enabled: true,
         }
     }
 
-    async fn print_help(
+    /// Prints the heading above the commands of a category.
+    async fn print_heading<IO: IoDevice>(
         &self,
         output: &mut Output<'_, IO>,
-        show_hidden: bool,
+        category: &str,
     ) -> Result<(), MenuError> {
-        self.cmd.print_help(output, show_hidden).await?;
-        self.next_router.print_help(output, show_hidden).await
+        outwriteln!(
+            output,
+            "{}{}{}",
+            self.category_prefix,
+            category,
+            self.category_suffix
+        )
     }
 }
 
-/// You probably don't want to implement this trait yourself! This trait is used to make
-/// the internal structure of the Menu opaque to the user which is useful for implementing
-/// the builder pattern in the way it has been done here.
-///
-/// What this means is that when you build a Menu, all you know is that you will end up with
-/// "something" that has the interface specified by this trait.
-pub trait Menu<IO: IoDevice, S> {
-    /// Registers a new command with the Menu.
-    fn with_command<CMD: Command<IO, S>>(self) -> impl Menu<IO, S>;
+/// Implements the built-in help command, which either lists all commands, shows the
+/// detailed help of a single command or lists the commands of a single category.
+async fn execute_help<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    help: &HelpCommand,
+    args: Option<&str>,
+    output: &mut Output<'_, IO>,
+) -> Result<(), MenuError> {
+    let show_hidden = match args {
+        None => false,
+        Some(flag) if help.hidden_flag == Some(flag) => true,
+        Some(name) => {
+            let level = output.privilege_level;
+            return match find_command(router, state, name).filter(|info| info.permitted(level)) {
+                Some(info) => print_command_details(help, &info, output).await,
+                None => {
+                    if print_category(router, state, help, output, name).await?
+                        || print_module(router, state, help, output, name).await?
+                    {
+                        Ok(())
+                    } else {
+                        Err(MenuError::UnknownCommand)
+                    }
+                }
+            };
+        }
+    };
 
-    /// Runs the Menu until it encounters an unrecoverable error or its `IODevice` disconnects.
-    fn run(self) -> impl Future<Output = Result<(), MenuError>>;
+    if !help.header.is_empty() {
+        outwriteln!(output, "{}", help.header)?;
+    }
+    print_command_list(router, state, help, output, show_hidden).await
 }
 
-impl<IO: IoDevice, S, HeadRouter: Router<IO, S>> Menu<IO, S> for MenuImpl<'_, IO, S, HeadRouter> {
-    fn with_command<CMD: Command<IO, S>>(self) -> impl Menu<IO, S> {
-        let name = CMD::name();
+async fn print_command_list<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    help: &HelpCommand,
+    output: &mut Output<'_, IO>,
+    show_hidden: bool,
+) -> Result<(), MenuError> {
+    let level = output.privilege_level;
+    let listed = |info: &CommandInfo| (show_hidden || !info.hidden) && info.permitted(level);
+    let name_width = name_width(router, state, listed);
+
+    // Commands without a category come first, followed by one section per category
+    print_section(router, state, help, output, name_width, |info| {
+        listed(info) && info.category.is_none()
+    })
+    .await?;
+
+    // Sections are separated by a blank line, but the first one follows the header directly
+    let mut printed =
+        command_infos_in(router, state).any(|info| listed(&info) && info.category.is_none());
+    let mut previous_category = None;
+    while let Some(category) = next_category(router, state, help.order, listed, previous_category) {
+        if printed {
+            outwriteln!(output, "")?;
+        }
+        help.print_heading(output, category).await?;
+        printed = true;
+        print_section(router, state, help, output, name_width, |info| {
+            listed(info) && info.category == Some(category)
+        })
+        .await?;
+        previous_category = Some(category);
+    }
 
-        // TODO: return errors for invalid commands instead of pannicing
-        assert_ne!(name, "help");
-        assert!(name.find([' ']).is_none());
+    Ok(())
+}
 
-        let new_router = NormalRouter {
-            cmd: CommandHolder::<IO, S, CMD>::new(),
-            next_router: self.head_router,
-        };
+/// Lists the visible commands of a single category, which is looked up case-insensitively.
+/// Returns `false` if there is no such category.
+async fn print_category<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    help: &HelpCommand,
+    output: &mut Output<'_, IO>,
+    category: &str,
+) -> Result<bool, MenuError> {
+    let level = output.privilege_level;
+    let in_category = |info: &CommandInfo| {
+        !info.hidden
+            && info.permitted(level)
+            && info
+                .category
+                .is_some_and(|c| c.eq_ignore_ascii_case(category))
+    };
+
+    let Some(category) = command_infos_in(router, state)
+        .find(in_category)
+        .and_then(|info| info.category)
+    else {
+        return Ok(false);
+    };
+
+    help.print_heading(output, category).await?;
+    let name_width = name_width(router, state, in_category);
+    print_section(router, state, help, output, name_width, in_category).await?;
+    Ok(true)
+}
 
-        MenuImpl {
-            head_router: new_router,
-            input_buffer: self.input_buffer,
-            input_buffer_idx: self.input_buffer_idx,
-            output_buffer: self.output_buffer,
-            output_buffer_idx: self.output_buffer_idx,
-            io_device: self.io_device,
-            state: self.state,
+/// Lists the visible commands of the module with the given prefix. Returns `false` if there is
+/// no such module.
+async fn print_module<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    help: &HelpCommand,
+    output: &mut Output<'_, IO>,
+    prefix: &str,
+) -> Result<bool, MenuError> {
+    let level = output.privilege_level;
+    let in_module =
+        |info: &CommandInfo| !info.hidden && info.permitted(level) && info.prefix == Some(prefix);
+
+    if !command_infos_in(router, state).any(|info| in_module(&info)) {
+        return Ok(false);
+    }
+
+    let name_width = name_width(router, state, in_module);
+    print_section(router, state, help, output, name_width, in_module).await?;
+    Ok(true)
+}
+
+fn name_width<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    filter: impl Fn(&CommandInfo) -> bool,
+) -> usize {
+    command_infos_in(router, state)
+        .filter(|info| filter(info))
+        .map(|info| info.name_width())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the category that follows `previous` when listing categories in the given order.
+/// In registration order, categories are ordered by their first command.
+fn next_category<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    order: HelpOrder,
+    listed: impl Fn(&CommandInfo) -> bool,
+    previous: Option<&'static str>,
+) -> Option<&'static str> {
+    let categories = || {
+        command_infos_in(router, state)
+            .filter(|info| listed(info))
+            .filter_map(|info| info.category)
+    };
+
+    match order {
+        HelpOrder::Registration => {
+            let first_position = |category| categories().position(|c| c == category);
+            let previous_position = previous.and_then(first_position);
+
+            categories()
+                .enumerate()
+                .filter(|(position, _)| previous_position.is_none_or(|p| *position > p))
+                .find(|(position, category)| first_position(category) == Some(*position))
+                .map(|(_, category)| category)
         }
+        HelpOrder::Alphabetical => categories()
+            .filter(|category| previous.is_none_or(|p| *category > p))
+            .min(),
     }
+}
 
-    async fn run(mut self) -> Result<(), MenuError> {
-        loop {
-            match self.read_input().await {
-                Ok(_) => {}
-                Err(MenuError::Io(IoDeviceError::Disconnected)) => return Ok(()),
-                other => return other,
+async fn print_section<IO: IoDevice, S>(
+    router: &impl Router<IO, S>,
+    state: &S,
+    help: &HelpCommand,
+    output: &mut Output<'_, IO>,
+    name_width: usize,
+    filter: impl Fn(&CommandInfo) -> bool,
+) -> Result<(), MenuError> {
+    match help.order {
+        HelpOrder::Registration => {
+            for info in command_infos_in(router, state).filter(|info| filter(info)) {
+                print_command_line(help, &info, name_width, output).await?;
+            }
+        }
+        HelpOrder::Alphabetical => {
+            // Repeatedly look for the next name to avoid having to sort (and allocate)
+            let mut previous_name = None;
+            while let Some(info) = command_infos_in(router, state)
+                .filter(|info| filter(info))
+                .filter(|info| previous_name.is_none_or(|name| (info.prefix, info.name) > name))
+                .min_by_key(|info| (info.prefix, info.name))
+            {
+                print_command_line(help, &info, name_width, output).await?;
+                previous_name = Some((info.prefix, info.name));
             }
         }
     }
-}
 
-struct MenuImpl<'d, IO: IoDevice, S, HeadRouter: Router<IO, S>> {
-    head_router: HeadRouter,
-    input_buffer: &'d mut [u8],
-    input_buffer_idx: usize,
-    output_buffer: &'d mut [u8],
-    output_buffer_idx: usize,
-    io_device: &'d mut IO,
-    state: &'d mut S,
+    Ok(())
 }
 
-fn parse_line(cmd_string: &[u8]) -> Result<(&str, Option<&str>), Utf8Error> {
-    let mut space_idx = 0;
-
-    for (i, char) in cmd_string.iter().enumerate() {
-        if *char == b' ' {
-            space_idx = i;
-            break;
+/// Prints the help string of a command, aligned to a column after the longest command name.
+async fn print_command_line<IO: IoDevice>(
+    help: &HelpCommand,
+    info: &CommandInfo,
+    name_width: usize,
+    output: &mut Output<'_, IO>,
+) -> Result<(), MenuError> {
+    // Help strings start after the prefix, the name, a colon and a space
+    let prefix_width = help.prefix.chars().count();
+    let indent = prefix_width + name_width + 2;
+
+    output.write_str(help.prefix)?;
+    info.write_name(output)?;
+    output.write_str(":")?;
+    let mut column = prefix_width + info.name_width() + 1;
+    write_spaces(output, indent - column)?;
+    column = indent;
+
+    for (i, word) in info.help_string.split_whitespace().enumerate() {
+        let word_width = word.chars().count();
+
+        if i > 0 {
+            if help
+                .wrap_width
+                .is_some_and(|width| column + 1 + word_width > width)
+            {
+                output.write_str("\n")?;
+                output.flush_buffer().await?;
+                write_spaces(output, indent)?;
+                column = indent;
+            } else {
+                output.write_str(" ")?;
+                column += 1;
+            }
         }
+
+        output.write_str(word)?;
+        column += word_width;
     }
 
-    let after_space_idx = space_idx + 1;
+    output.write_str("\n")?;
+    output.flush_buffer().await?;
+    Ok(())
+}
 
-    if space_idx > 0 && after_space_idx < cmd_string.len() {
-        let cmd = str::from_utf8(&cmd_string[..space_idx])?;
-        let args = str::from_utf8(&cmd_string[after_space_idx..])?;
-        Ok((cmd, Some(args)))
-    } else {
-        let cmd = str::from_utf8(cmd_string)?;
-        Ok((cmd, None))
+fn write_spaces<IO: IoDevice>(output: &mut Output<'_, IO>, count: usize) -> Result<(), MenuError> {
+    for _ in 0..count {
+        output.write_str(" ")?;
     }
+    Ok(())
 }
 
-async fn try_print_error<IO: IoDevice>(
+async fn print_command_details<IO: IoDevice>(
+    help: &HelpCommand,
+    info: &CommandInfo,
     output: &mut Output<'_, IO>,
-    e: MenuError,
 ) -> Result<(), MenuError> {
-    match e {
-        MenuError::Io(IoDeviceError::Disconnected) => Err(e),
-        MenuError::UnknownCommand => {
-            outwriteln!(output, "Unknown command")
-        }
-        MenuError::Io(IoDeviceError::BufferOverflow) => {
-            outwriteln!(output, "IO buffer overflow")
-        }
-        MenuError::Utf8 => {
-            outwriteln!(output, "Input UTF8 error")
+    output.write_str(help.prefix)?;
+    info.write_name(output)?;
+    outwriteln!(output, ": {}", info.help_string)?;
+
+    if !info.aliases.is_empty() {
+        output.write(help.aliases_label).await?;
+        for (i, alias) in info.aliases.iter().enumerate() {
+            if i > 0 {
+                output.write(", ").await?;
+            }
+            output.write(alias).await?;
         }
-        MenuError::InputBufferOverflow => {
-            outwriteln!(output, "Input buffer overflowed & dumped")
+        output.write("\n").await?;
+    }
+
+    if let Some(long_help) = info.long_help {
+        // Long help texts are written directly since they may not fit into the output buffer
+        output.write("\n").await?;
+        output.write(long_help).await?;
+        if !long_help.ends_with('\n') {
+            output.write("\n").await?;
         }
+    }
+
+    Ok(())
+}
 
-        // We need to abort when then output buffer is full since that
-        // condition prevents us from outputting an error message.
-        MenuError::OutputBufferOverflow => Err(e),
+fn parse_line(cmd_string: &[u8]) -> Result<(&str, Option<&str>), Utf8Error> {
+    Ok(split_command(str::from_utf8(cmd_string)?))
+}
+
+/// Splits a line into the command and its arguments at the first space.
+fn split_command(line: &str) -> (&str, Option<&str>) {
+    match line.find(' ') {
+        Some(space_idx) if space_idx > 0 && space_idx + 1 < line.len() => {
+            (&line[..space_idx], Some(&line[space_idx + 1..]))
+        }
+        _ => (line, None),
     }
 }
 
-impl<IO: IoDevice, S, HeadRouter: Router<IO, S>> MenuImpl<'_, IO, S, HeadRouter> {
+impl<
+    IO: IoDevice,
+    S,
+    L: CommandList<IO, S>,
+    D: Dispatcher,
+    ER: ErrorRenderer<IO>,
+    T: Timer,
+    Module: CommandSet<IO, S>,
+    H: Hook<IO, S>,
+    V: PasswordVerifier,
+> MenuImpl<'_, IO, S, L, D, ER, T, Module, H, V>
+{
     async fn read_input(&mut self) -> Result<(), MenuError> {
         let read_result = {
             if self.input_buffer_idx < self.input_buffer.len() {
                 let buf = &mut self.input_buffer[self.input_buffer_idx..];
-                self.io_device.read_packet(buf).await.map_err(|e| match e {
-                    IoDeviceError::BufferOverflow => MenuError::InputBufferOverflow,
-                    other => MenuError::Io(other),
-                })
+                let io_device = &mut *self.io_device;
+                let read = async {
+                    // Transient conditions are retried within the same idle period, so that
+                    // a device that keeps timing out does not keep restarting the idle timeout
+                    loop {
+                        match io_device.read_packet(buf).await {
+                            Err(IoDeviceError::Timeout | IoDeviceError::WouldBlock) => {
+                                yield_now().await
+                            }
+                            res => return res,
+                        }
+                    }
+                };
+                let timeout = self.idle_timer.delay_ms(self.idle_timeout_ms);
+
+                match select(read, timeout).await {
+                    Either::First(res) => res.map_err(|e| match e {
+                        IoDeviceError::BufferOverflow => MenuError::InputBufferOverflow,
+                        other => MenuError::Io(other),
+                    }),
+                    Either::Second(()) => {
+                        // Start over with a clean session in case the menu keeps running
+                        self.discarding_input = false;
+                        if let Some(login) = &mut self.login {
+                            login.logout();
+                        }
+                        Err(MenuError::IdleTimeout)
+                    }
+                }
             } else {
+                // The current line does not fit into the input buffer. Its remainder must not be
+                // executed, so all input is dropped until the line has ended.
+                self.discarding_input = true;
                 Err(MenuError::InputBufferOverflow)
             }
         };
@@ -385,29 +3133,74 @@ impl<IO: IoDevice, S, HeadRouter: Router<IO, S>> MenuImpl<'_, IO, S, HeadRouter>
         match read_result {
             Ok(n_bytes_read) => {
                 self.input_buffer_idx += n_bytes_read;
+                if self.discarding_input {
+                    self.discard_until_line_end();
+                }
                 self.process_lines_in_buffer().await
             }
             Err(e) => {
+                // The remainder of an interrupted line must not be executed as a new command
+                self.discarding_input |= match e {
+                    MenuError::InputBufferOverflow
+                    | MenuError::Io(
+                        IoDeviceError::Framing | IoDeviceError::Parity | IoDeviceError::Other(_),
+                    ) => self.input_buffer_idx > 0,
+                    _ => false,
+                };
                 self.input_buffer_idx = 0;
                 defmt::debug!("Input buffer dumped due to read error");
+                let disconnected = matches!(e, MenuError::Io(IoDeviceError::Disconnected));
 
                 let output = &mut Output {
                     io_device: self.io_device,
                     buffer: self.output_buffer,
                     buffer_idx: &mut self.output_buffer_idx,
+                    pager: Pager::new(self.page_height),
+                    prompting: &Cell::new(false),
+                    privilege_level: privilege_level(&self.login),
                 };
 
                 // Try to print an error message before giving up
-                try_print_error(output, e).await
+                let context = ErrorContext {
+                    line: None,
+                    command: None,
+                    suggestion: None,
+                };
+                self.error_renderer.render(e, &context, output).await?;
+
+                // A disconnected device cannot provide any more input, so the menu stops
+                // even if the renderer chose to keep it running
+                match disconnected {
+                    true => Err(MenuError::Io(IoDeviceError::Disconnected)),
+                    false => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn discard_until_line_end(&mut self) {
+        let full_input = &self.input_buffer[..self.input_buffer_idx];
+        match full_input.iter().position(|c| *c == b'\n') {
+            Some(line_end_idx) => {
+                let next_line_start_idx = line_end_idx + 1;
+                self.input_buffer
+                    .copy_within(next_line_start_idx..self.input_buffer_idx, 0);
+                self.input_buffer_idx -= next_line_start_idx;
+                self.discarding_input = false;
             }
+            None => self.input_buffer_idx = 0,
         }
     }
 
     async fn process_lines_in_buffer(&mut self) -> Result<(), MenuError> {
+        let prompting = Cell::new(false);
         let output = &mut Output {
             io_device: self.io_device,
             buffer: self.output_buffer,
             buffer_idx: &mut self.output_buffer_idx,
+            pager: Pager::new(self.page_height),
+            prompting: &prompting,
+            privilege_level: privilege_level(&self.login),
         };
 
         let last_line_start_idx = {
@@ -419,27 +3212,121 @@ impl<IO: IoDevice, S, HeadRouter: Router<IO, S>> MenuImpl<'_, IO, S, HeadRouter>
                 assert!(line_start_idx < full_input.len());
 
                 let line = &full_input[line_start_idx..line_end_idx];
-                let (cmd, args) = parse_line(line)?;
-
-                defmt::trace!("Picomenu processing line: {:?}", line);
+                line_start_idx = line_end_idx + 1;
 
-                if cmd == "help" {
-                    let show_hidden = args == Some("--hidden");
-                    outwriteln!(output, "AVAILABLE COMMANDS:\n")?;
-                    self.head_router.print_help(output, show_hidden).await?;
-                } else {
-                    let res = self
-                        .head_router
-                        .execute_or_forward(cmd, args, output, self.state)
-                        .await;
+                output.pager.reset();
+                output.privilege_level = privilege_level(&self.login);
+
+                if let Some(login) = &mut self.login
+                    && login.awaiting_password
+                {
+                    // Entering the password completes the login command, which is why the
+                    // hooks are called for it once more
+                    let res = match str::from_utf8(line) {
+                        Ok(password) => {
+                            let hook = &mut self.commands.hook;
+                            login
+                                .execute(LOGIN_NAME, Some(password), hook, output, self.state)
+                                .await
+                        }
+                        Err(e) => Err(e.into()),
+                    };
 
                     if let Err(e) = res {
-                        // Try to print an error message before giving up
-                        try_print_error(output, e).await?
+                        // The line is left out since it contains the password
+                        let context = ErrorContext {
+                            line: None,
+                            command: Some(LOGIN_NAME),
+                            suggestion: None,
+                        };
+                        self.error_renderer.render(e, &context, output).await?;
                     }
+                    continue;
                 }
 
-                line_start_idx = line_end_idx + 1;
+                // Passwords must not end up in the logs
+                if !line.starts_with(LOGIN_NAME.as_bytes()) {
+                    defmt::trace!("Picomenu processing line: {:?}", line);
+                }
+
+                let (cmd, args) = match parse_line(line) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        // Lines that are not valid UTF8 are reported and skipped
+                        let context = ErrorContext {
+                            line: Some(line),
+                            command: None,
+                            suggestion: None,
+                        };
+                        self.error_renderer
+                            .render(e.into(), &context, output)
+                            .await?;
+                        continue;
+                    }
+                };
+
+                let login_cmd = self.login.as_ref().and_then(|_| Login::<V>::command(cmd));
+                let is_login = login_cmd.is_some();
+                let res = match (&mut self.login, login_cmd) {
+                    (Some(login), Some(name)) => {
+                        let hook = &mut self.commands.hook;
+                        login.execute(name, args, hook, output, self.state).await
+                    }
+                    _ => {
+                        // The idle timeout also ends sessions that were left at the pager prompt
+                        let execute = self.commands.execute(cmd, args, output, self.state);
+                        let timeout =
+                            expire_prompt(&mut self.idle_timer, self.idle_timeout_ms, &prompting);
+                        match select(execute, timeout).await {
+                            Either::First(res) => res,
+                            Either::Second(()) => Err(MenuError::IdleTimeout),
+                        }
+                    }
+                };
+                let timed_out = res == Err(MenuError::IdleTimeout);
+                if timed_out {
+                    // Drop the rest of the output, and make sure that the pager does not
+                    // prompt again while reporting the timeout
+                    *output.buffer_idx = 0;
+                    output.pager.reset();
+                }
+
+                if let Err(e) = res {
+                    // For `help <name>`, the command that could not be found is the argument
+                    let is_help = self.commands.router.find(None, cmd) == Some(HELP_IDX);
+                    let failed_cmd = match (is_help, args) {
+                        (true, Some(name)) => name,
+                        _ => cmd,
+                    };
+
+                    let suggestion = match e {
+                        MenuError::UnknownCommand => closest_command(
+                            &self.commands.router,
+                            self.state,
+                            failed_cmd,
+                            output.privilege_level,
+                        ),
+                        _ => None,
+                    };
+
+                    // Try to print an error message before giving up. The line of a login
+                    // is left out since it may contain the password.
+                    let context = ErrorContext {
+                        line: (!is_login).then_some(line),
+                        command: Some(failed_cmd),
+                        suggestion,
+                    };
+                    self.error_renderer.render(e, &context, output).await?
+                }
+
+                if timed_out {
+                    // Start over with a clean session, without any of the input typed ahead
+                    if let Some(login) = &mut self.login {
+                        login.logout();
+                    }
+                    line_start_idx = full_input.len();
+                    break;
+                }
             }
             line_start_idx
         };
@@ -467,13 +3354,43 @@ pub fn make_menu<'d, IO: IoDevice, S>(
     output_buffer: &'d mut [u8],
 ) -> impl Menu<IO, S> + use<'d, IO, S> {
     MenuImpl {
-        head_router: FinalRouter {},
+        commands: make_command_tree(),
         input_buffer,
         input_buffer_idx: 0,
+        discarding_input: false,
         output_buffer,
         output_buffer_idx: 0,
         io_device,
         state,
+        page_height: None,
+        error_renderer: DefaultErrorRenderer {},
+        idle_timer: NoTimer {},
+        idle_timeout_ms: 0,
+        login: None::<Login<NoVerifier>>,
+    }
+}
+
+/// Returns an empty sub-menu, which can be extended with commands using the `SubMenu` trait and
+/// then mounted in another menu using `Menu::with_submenu`. It uses the `IoDevice`, login,
+/// pager, error renderer and idle timeout of the menu it is mounted in.
+pub fn make_submenu<IO: IoDevice, S>() -> impl SubMenu<IO, S> {
+    SubMenuImpl {
+        commands: make_command_tree(),
+    }
+}
+
+fn make_command_tree<IO: IoDevice, S>()
+-> CommandTree<IO, S, NoCommands, ChainDispatcher, NoModule, NoHook> {
+    CommandTree {
+        router: MenuRouter {
+            help: Some(HelpCommand::default()),
+            commands: NoCommands {},
+        },
+        dispatcher: ChainDispatcher {},
+        hook: NoHook {},
+        _io_marker: PhantomData,
+        _state_marker: PhantomData,
+        _module_marker: PhantomData,
     }
 }
 
@@ -496,4 +3413,13 @@ mod test {
         assert_eq!(cmd, "mycommand");
         assert_eq!(args, None);
     }
+
+    #[test]
+    fn computes_edit_distance() {
+        assert_eq!(edit_distance(b"status", b"status"), Some(0));
+        assert_eq!(edit_distance(b"stauts", b"status"), Some(1));
+        assert_eq!(edit_distance(b"stat", b"status"), Some(2));
+        assert_eq!(edit_distance(b"", b"help"), Some(4));
+        assert_eq!(edit_distance(&[b'a'; 33], b"help"), None);
+    }
 }
@@ -0,0 +1,380 @@
+use picomenu::*;
+use std::{collections::VecDeque, string::String};
+
+struct MockIo {
+    received: VecDeque<String>,
+    to_send: VecDeque<Vec<u8>>,
+}
+
+impl MockIo {
+    fn new() -> Self {
+        Self {
+            received: Default::default(),
+            to_send: Default::default(),
+        }
+    }
+
+    fn read(&mut self) -> String {
+        self.received.pop_front().unwrap()
+    }
+
+    fn read_all(&mut self) -> String {
+        self.received.drain(..).collect()
+    }
+
+    fn queue_to_send(&mut self, msg: &str) {
+        self.to_send.push_back(msg.as_bytes().to_vec());
+    }
+}
+
+impl IoDevice for MockIo {
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), IoDeviceError> {
+        let new_string = String::from_utf8(data.to_vec()).unwrap();
+        self.received.push_back(new_string);
+        Ok(())
+    }
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, IoDeviceError> {
+        let bytes_to_send = self
+            .to_send
+            .pop_front()
+            .ok_or(IoDeviceError::Disconnected)?;
+        data[..bytes_to_send.len()].clone_from_slice(&bytes_to_send);
+        Ok(bytes_to_send.len())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    version: u32,
+}
+
+#[picomenu::command(name = "version", help = "Shows version")]
+async fn show_version<IO: IoDevice>(
+    output: &mut Output<'_, IO>,
+    state: &mut State,
+) -> Result<(), MenuError> {
+    outwriteln!(output, "Version: {}", state.version)
+}
+
+/// Sets the version
+#[picomenu::command(aliases = ["sv"], category = "Settings")]
+async fn set_version<IO: IoDevice>(
+    version: u32,
+    output: &mut Output<'_, IO>,
+    state: &mut State,
+) -> Result<(), MenuError> {
+    state.version = version;
+    outwriteln!(output, "Version set")
+}
+
+/// Greets someone, optionally more than once
+#[picomenu::command(long_help = "Usage: greet <name> [times]")]
+async fn greet<IO: IoDevice>(
+    name: &str,
+    times: Option<u8>,
+    output: &mut Output<'_, IO>,
+) -> Result<(), MenuError> {
+    for _ in 0..times.unwrap_or(1) {
+        outwriteln!(output, "Hello {}!", name)?;
+    }
+    Ok(())
+}
+
+#[picomenu::command(name = "secret", help = "Does secret stuff", hidden)]
+async fn secret() -> Result<(), MenuError> {
+    Ok(())
+}
+
+/// Migrates the settings of a prerelease version
+#[picomenu::command(hidden_when = is_released, enabled_when = is_prerelease)]
+async fn migrate<IO: IoDevice>(
+    output: &mut Output<'_, IO>,
+    _state: &mut State,
+) -> Result<(), MenuError> {
+    outwriteln!(output, "Migrated")
+}
+
+fn is_released(state: &State) -> bool {
+    state.version >= 1
+}
+
+fn is_prerelease(state: &State) -> bool {
+    state.version < 1
+}
+
+#[derive(CommandSet)]
+#[command_set(handler = handle, state = State, category = "Device")]
+enum DeviceCommand<'a> {
+    /// Shows the device name
+    Name,
+
+    /// Renames the device
+    #[command_set(aliases = ["mv"])]
+    SetName { name: &'a str, repeat: Option<u8> },
+
+    /// Resets the device
+    #[command_set(name = "reset", hidden)]
+    FactoryReset { force: bool, delay_ms: u32 },
+}
+
+async fn handle<IO: IoDevice>(
+    cmd: DeviceCommand<'_>,
+    output: &mut Output<'_, IO>,
+    state: &mut State,
+) -> Result<(), MenuError> {
+    match cmd {
+        DeviceCommand::Name => outwriteln!(output, "Device v{}", state.version),
+        DeviceCommand::SetName { name, repeat } => {
+            for _ in 0..repeat.unwrap_or(1) {
+                outwriteln!(output, "Renamed to {}", name)?;
+            }
+            Ok(())
+        }
+        DeviceCommand::FactoryReset { force, delay_ms } => {
+            outwriteln!(output, "Reset (force: {}) in {}ms", force, delay_ms)
+        }
+    }
+}
+
+#[derive(CommandSet)]
+#[command_set(handler = handle_led, prefix = "led")]
+enum LedCommand {
+    /// Turns the LED on
+    On { brightness: Option<u8> },
+
+    /// Turns the LED off
+    Off,
+
+    /// Sets the PWM duty cycle
+    SetPWMDuty { percent: u8 },
+}
+
+async fn handle_led<IO: IoDevice, S>(
+    cmd: LedCommand,
+    output: &mut Output<'_, IO>,
+    _state: &mut S,
+) -> Result<(), MenuError> {
+    match cmd {
+        LedCommand::On { brightness } => {
+            outwriteln!(output, "LED at {}%", brightness.unwrap_or(100))
+        }
+        LedCommand::Off => outwriteln!(output, "LED off"),
+        LedCommand::SetPWMDuty { percent } => outwriteln!(output, "Duty at {}%", percent),
+    }
+}
+
+fn build_menu<'d>(
+    device: &'d mut MockIo,
+    state: &'d mut State,
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+) -> Result<impl Menu<MockIo, State> + use<'d>, RegistrationError> {
+    make_menu(device, state, input_buffer, output_buffer)
+        .with_command::<show_version>()?
+        .with_command::<set_version>()?
+        .with_command::<greet>()?
+        .with_command::<secret>()
+}
+
+#[tokio::test]
+async fn runs_generated_commands() {
+    let mut device = MockIo::new();
+    device.queue_to_send("sv 3\n");
+    device.queue_to_send("version\n");
+    device.queue_to_send("greet World 2\n");
+    device.queue_to_send("secret\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Version set\n");
+    assert_eq!(device.read(), "Version: 3\n");
+    assert_eq!(device.read(), "Hello World!\n");
+    assert_eq!(device.read(), "Hello World!\n");
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn reports_invalid_arguments() {
+    let mut device = MockIo::new();
+    device.queue_to_send("set_version\n");
+    device.queue_to_send("set_version three\n");
+    device.queue_to_send("set_version 3 4\n");
+    device.queue_to_send("greet\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Error: Missing argument <version>\n");
+    assert_eq!(device.read(), "Error: Invalid argument <version>\n");
+    assert_eq!(device.read(), "Error: Too many arguments\n");
+    assert_eq!(device.read(), "Error: Missing argument <name>\n");
+}
+
+#[tokio::test]
+async fn prints_generated_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+    device.queue_to_send("help greet\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > version:     Shows version\n\
+         > greet:       Greets someone, optionally more than once\n\
+         \n\
+         Settings:\n\
+         > set_version: Sets the version\n\
+         > greet: Greets someone, optionally more than once\n\
+         \n\
+         Usage: greet <name> [times]\n"
+    );
+}
+
+#[tokio::test]
+async fn applies_state_predicates() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+    device.queue_to_send("migrate\n");
+    device.queue_to_send("set_version 1\n");
+    device.queue_to_send("migrate\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<migrate>()
+    .unwrap()
+    .with_command::<set_version>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\
+         \n\
+         > migrate:     Migrates the settings of a prerelease version\n\
+         \n\
+         Settings:\n\
+         > set_version: Sets the version\n\
+         Migrated\n\
+         Version set\n\
+         Command not available in current mode\n\
+         AVAILABLE COMMANDS:\n\
+         \n\
+         Settings:\n\
+         > set_version: Sets the version\n"
+    );
+}
+
+#[tokio::test]
+async fn runs_command_set() {
+    let mut device = MockIo::new();
+    device.queue_to_send("name\n");
+    device.queue_to_send("set_name Box 2\n");
+    device.queue_to_send("mv Crate\n");
+    device.queue_to_send("reset 100 --force\n");
+    device.queue_to_send("reset 200\n");
+    device.queue_to_send("reset --forced 200\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_module::<DeviceCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Device v0\n");
+    assert_eq!(device.read(), "Renamed to Box\n");
+    assert_eq!(device.read(), "Renamed to Box\n");
+    assert_eq!(device.read(), "Renamed to Crate\n");
+    assert_eq!(device.read(), "Reset (force: true) in 100ms\n");
+    assert_eq!(device.read(), "Reset (force: false) in 200ms\n");
+    assert_eq!(device.read(), "Error: Invalid argument <delay_ms>\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\
+         \n\
+         Device:\n\
+         > name:     Shows the device name\n\
+         > set_name: Renames the device\n"
+    );
+}
+
+#[tokio::test]
+async fn runs_prefixed_command_set() {
+    let mut device = MockIo::new();
+    device.queue_to_send("led on 40\n");
+    device.queue_to_send("led off\n");
+    device.queue_to_send("led set_pwm_duty 25\n");
+    device.queue_to_send("off\n");
+    device.queue_to_send("help led\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_module::<LedCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "LED at 40%\n");
+    assert_eq!(device.read(), "LED off\n");
+    assert_eq!(device.read(), "Duty at 25%\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(
+        device.read_all(),
+        "> led on:           Turns the LED on\n\
+         > led off:          Turns the LED off\n\
+         > led set_pwm_duty: Sets the PWM duty cycle\n"
+    );
+}
@@ -3,7 +3,8 @@ use std::{collections::VecDeque, string::String};
 
 struct MockIo {
     received: VecDeque<String>,
-    to_send: VecDeque<String>,
+    to_send: VecDeque<Result<Vec<u8>, IoDeviceError>>,
+    split_packets: bool,
 }
 
 impl MockIo {
@@ -11,6 +12,15 @@ impl MockIo {
         Self {
             received: Default::default(),
             to_send: Default::default(),
+            split_packets: false,
+        }
+    }
+
+    /// Packets that do not fit into the read buffer get split instead of overflowing it.
+    fn with_split_packets() -> Self {
+        Self {
+            split_packets: true,
+            ..Self::new()
         }
     }
 
@@ -18,8 +28,25 @@ impl MockIo {
         self.received.pop_front().unwrap()
     }
 
+    fn read_all(&mut self) -> String {
+        self.received.drain(..).collect()
+    }
+
     fn queue_to_send(&mut self, msg: &str) {
-        self.to_send.push_back(msg.to_string());
+        self.queue_bytes_to_send(msg.as_bytes());
+    }
+
+    fn queue_bytes_to_send(&mut self, bytes: &[u8]) {
+        self.to_send.push_back(Ok(bytes.to_vec()));
+    }
+
+    fn queue_error(&mut self, error: IoDeviceError) {
+        self.to_send.push_back(Err(error));
+    }
+
+    /// Queues a read that never receives any input, as if the user had walked away.
+    fn queue_idle(&mut self) {
+        self.to_send.push_back(Ok(Vec::new()));
     }
 }
 
@@ -32,12 +59,20 @@ impl IoDevice for MockIo {
 
     async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, IoDeviceError> {
         if let Some(to_send) = self.to_send.pop_front() {
-            let bytes_to_send = to_send.as_bytes();
+            let bytes_to_send = to_send?;
             let len_to_send = bytes_to_send.len();
+            if len_to_send == 0 {
+                std::future::pending::<()>().await;
+            }
 
             if len_to_send < data.len() {
-                data[..len_to_send].clone_from_slice(bytes_to_send);
+                data[..len_to_send].clone_from_slice(&bytes_to_send);
                 Ok(len_to_send)
+            } else if self.split_packets {
+                let (head, tail) = bytes_to_send.split_at(data.len());
+                data.clone_from_slice(head);
+                self.to_send.push_front(Ok(tail.to_vec()));
+                Ok(head.len())
             } else {
                 Err(IoDeviceError::BufferOverflow)
             }
@@ -120,6 +155,10 @@ impl<IO: IoDevice> Command<IO, State> for HelloCommand {
         "Says hello"
     }
 
+    fn long_help() -> Option<&'static str> {
+        Some("Usage: hello <name>\nExample: hello World")
+    }
+
     async fn execute(
         args: Option<&str>,
         output: &mut Output<'_, IO>,
@@ -149,254 +188,2845 @@ impl<IO: IoDevice> Command<IO, State> for HiddenCommand {
         true
     }
 
-    async fn execute(
-        _args: Option<&str>,
-        output: &mut Output<'_, IO>,
-        _state: &mut State,
-    ) -> Result<(), MenuError> {
-        output.write(SECRET_RESPONSE).await?;
-        Ok(())
-    }
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        output.write(SECRET_RESPONSE).await?;
+        Ok(())
+    }
+}
+
+struct DumpCommand {}
+impl<IO: IoDevice> Command<IO, State> for DumpCommand {
+    fn name() -> &'static str {
+        "dump"
+    }
+
+    fn help_string() -> &'static str {
+        "Dumps many lines"
+    }
+
+    fn category() -> Option<&'static str> {
+        Some("Diagnostics")
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        for i in 1..=5 {
+            outwriteln!(output, "Line {}", i)?;
+        }
+        Ok(())
+    }
+}
+
+struct FailingCommand {}
+impl<IO: IoDevice> Command<IO, State> for FailingCommand {
+    fn name() -> &'static str {
+        "sensor"
+    }
+
+    fn help_string() -> &'static str {
+        "Reads a broken sensor"
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        _output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        Err(MenuError::Command("Sensor read failed"))
+    }
+}
+
+struct TerseErrorRenderer {}
+impl<IO: IoDevice> ErrorRenderer<IO> for TerseErrorRenderer {
+    async fn render(
+        &self,
+        error: MenuError,
+        context: &ErrorContext<'_>,
+        output: &mut Output<'_, IO>,
+    ) -> Result<(), MenuError> {
+        match error {
+            MenuError::UnknownCommand => {
+                let line = context.line.unwrap_or_default();
+                let line = std::str::from_utf8(line).unwrap_or("?");
+                outwriteln!(output, "ERR unknown '{}'", line)
+            }
+            MenuError::Command(_) => Err(error),
+            other => DefaultErrorRenderer {}.render(other, context, output).await,
+        }
+    }
+}
+
+struct EchoLineErrorRenderer {}
+impl<IO: IoDevice> ErrorRenderer<IO> for EchoLineErrorRenderer {
+    async fn render(
+        &self,
+        error: MenuError,
+        context: &ErrorContext<'_>,
+        output: &mut Output<'_, IO>,
+    ) -> Result<(), MenuError> {
+        if let MenuError::Io(_) = error {
+            return Err(error);
+        }
+        match context.line {
+            Some(line) => {
+                let line = std::str::from_utf8(line).unwrap_or("?");
+                outwriteln!(output, "ERR in '{}'", line)
+            }
+            None => outwriteln!(output, "ERR"),
+        }
+    }
+}
+
+macro_rules! named_command {
+    (
+        $cmd:ident,
+        $name:expr
+        $(, aliases = $aliases:expr)?
+        $(, category = $category:expr)?
+    ) => {
+        struct $cmd {}
+        impl<IO: IoDevice> Command<IO, State> for $cmd {
+            fn name() -> &'static str {
+                $name
+            }
+
+            $(
+                fn aliases() -> &'static [&'static str] {
+                    $aliases
+                }
+            )?
+
+            $(
+                fn category() -> Option<&'static str> {
+                    Some($category)
+                }
+            )?
+
+            fn help_string() -> &'static str {
+                "Does nothing"
+            }
+
+            async fn execute(
+                _args: Option<&str>,
+                _output: &mut Output<'_, IO>,
+                _state: &mut State,
+            ) -> Result<(), MenuError> {
+                Ok(())
+            }
+        }
+    };
+}
+
+named_command!(EmptyNameCommand, "");
+named_command!(SpacedNameCommand, "two words");
+named_command!(TabbedNameCommand, "two\twords");
+named_command!(HelpNameCommand, "help");
+named_command!(StNameCommand, "st");
+named_command!(PingCommand, "ping", category = "Network");
+named_command!(
+    RepeatedAliasCommand,
+    "repeat",
+    aliases = &["again", "again"]
+);
+
+const STATUS_RESPONSE: &str = "Status: OK\n";
+
+struct StatusCommand {}
+impl<IO: IoDevice> Command<IO, State> for StatusCommand {
+    fn name() -> &'static str {
+        "status"
+    }
+
+    fn help_string() -> &'static str {
+        "Shows status"
+    }
+
+    fn aliases() -> &'static [&'static str] {
+        &["st", "stat"]
+    }
+
+    fn category() -> Option<&'static str> {
+        Some("Diagnostics")
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        output.write(STATUS_RESPONSE).await?;
+        Ok(())
+    }
+}
+
+struct BufferingCommand {}
+impl<IO: IoDevice> Command<IO, State> for BufferingCommand {
+    fn name() -> &'static str {
+        "buffering"
+    }
+
+    fn help_string() -> &'static str {
+        "Keeps a large buffer while writing"
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        let buffer = [b'x'; 1024];
+        output.write(str::from_utf8(&buffer).unwrap()).await?;
+        Ok(())
+    }
+}
+
+/// Reads a register of one of several buses, counting how often it was used.
+struct ReadCommand {
+    name: &'static str,
+    bus: u8,
+    reads: u32,
+}
+
+impl<IO: IoDevice> CommandInstance<IO, State> for ReadCommand {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help_string(&self) -> &'static str {
+        "Reads a register"
+    }
+
+    async fn execute(
+        &mut self,
+        args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        self.reads += 1;
+        outwriteln!(
+            output,
+            "Bus {} register {}: read {}",
+            self.bus,
+            args.unwrap_or("0"),
+            self.reads
+        )
+    }
+}
+
+/// The commands of an IMU driver, which are registered with an `imu` prefix.
+struct ImuCommands {}
+impl<IO: IoDevice> CommandSet<IO, State> for ImuCommands {
+    fn register(
+        menu: impl SubMenu<IO, State>,
+    ) -> Result<impl SubMenu<IO, State>, RegistrationError> {
+        menu.with_command_instance(ReadCommand {
+            name: "read",
+            bus: 2,
+            reads: 0,
+        })?
+        .with_command::<VersionCommand>()
+    }
+
+    fn prefix() -> Option<&'static str> {
+        Some("imu")
+    }
+
+    fn category() -> Option<&'static str> {
+        Some("Sensors")
+    }
+}
+
+/// The commands of a power management driver, which are only grouped into a category.
+struct PowerCommands {}
+impl<IO: IoDevice> CommandSet<IO, State> for PowerCommands {
+    fn register(
+        menu: impl SubMenu<IO, State>,
+    ) -> Result<impl SubMenu<IO, State>, RegistrationError> {
+        menu.with_command::<TestCommand>()?
+            .with_command::<StatusCommand>()
+    }
+
+    fn category() -> Option<&'static str> {
+        Some("Power")
+    }
+}
+
+/// Prints a line before and after every command.
+struct LogHook {}
+impl<IO: IoDevice> Hook<IO, State> for LogHook {
+    async fn before(
+        &mut self,
+        command: &CommandContext<'_>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        match command.args {
+            Some(args) => outwriteln!(output, "Running {} {}", command.name, args),
+            None => outwriteln!(output, "Running {}", command.name),
+        }
+    }
+
+    async fn after(
+        &mut self,
+        command: &CommandContext<'_>,
+        result: Result<(), MenuError>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        let outcome = if result.is_ok() { "ok" } else { "failed" };
+        outwriteln!(output, "Finished {}: {}", command.name, outcome)?;
+        result
+    }
+}
+
+/// Only allows reading the version while the device is in safe mode.
+struct SafeModeHook {}
+impl<IO: IoDevice> Hook<IO, State> for SafeModeHook {
+    async fn before(
+        &mut self,
+        command: &CommandContext<'_>,
+        _output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        if matches!(command.name, "version" | "help") {
+            Ok(())
+        } else {
+            Err(MenuError::Command("Not available in safe mode"))
+        }
+    }
+}
+
+struct CalibrateCommand {}
+impl<IO: IoDevice> Command<IO, State> for CalibrateCommand {
+    fn name() -> &'static str {
+        "calibrate"
+    }
+
+    fn help_string() -> &'static str {
+        "Calibrates the sensors"
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        outwriteln!(output, "Calibrated")
+    }
+
+    fn required_level() -> u8 {
+        2
+    }
+}
+
+/// Grants level 1 for the user password and level 2 for the service password.
+struct TestVerifier {}
+impl PasswordVerifier for TestVerifier {
+    async fn verify(&mut self, password: &str) -> Option<u8> {
+        match password {
+            "user" => Some(1),
+            "service" => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// Only shown and available while the device is in factory mode.
+struct TrimCommand {}
+impl<IO: IoDevice> Command<IO, State> for TrimCommand {
+    fn name() -> &'static str {
+        "trim"
+    }
+
+    fn help_string() -> &'static str {
+        "Trims the oscillator"
+    }
+
+    async fn execute(
+        _args: Option<&str>,
+        output: &mut Output<'_, IO>,
+        _state: &mut State,
+    ) -> Result<(), MenuError> {
+        outwriteln!(output, "Trimmed")
+    }
+
+    fn is_hidden(state: &State) -> bool {
+        !state.factory_mode
+    }
+
+    fn is_enabled(state: &State) -> bool {
+        state.factory_mode
+    }
+}
+
+struct ImmediateTimer {}
+impl Timer for ImmediateTimer {
+    async fn delay_ms(&mut self, duration_ms: u32) {
+        assert_eq!(duration_ms, 60_000);
+    }
+}
+
+/// A `Timer` that expires once its delay has been polled a few times.
+struct PollingTimer {}
+impl Timer for PollingTimer {
+    async fn delay_ms(&mut self, _duration_ms: u32) {
+        let mut polls = 0;
+        std::future::poll_fn(|cx| {
+            polls += 1;
+            if polls < 3 {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            std::task::Poll::Ready(())
+        })
+        .await
+    }
+}
+
+/// An `IoDevice` whose driver keeps giving up on waiting for input that never arrives.
+#[derive(Default)]
+struct TimingOutIo {
+    received: String,
+    reads: usize,
+}
+
+impl IoDevice for TimingOutIo {
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), IoDeviceError> {
+        self.received.push_str(std::str::from_utf8(data).unwrap());
+        Ok(())
+    }
+
+    async fn read_packet(&mut self, _data: &mut [u8]) -> Result<usize, IoDeviceError> {
+        self.reads += 1;
+        assert!(self.reads < 100, "the idle timeout never expired");
+        tokio::task::yield_now().await;
+        Err(IoDeviceError::Timeout)
+    }
+}
+
+/// An `IoDevice` whose driver returns `WouldBlock` right away once its input has been read,
+/// without ever yielding to the executor.
+#[derive(Default)]
+struct BlockingIo {
+    input: Option<&'static str>,
+    received: String,
+    reads: usize,
+}
+
+impl IoDevice for BlockingIo {
+    async fn write_packet(&mut self, data: &[u8]) -> Result<(), IoDeviceError> {
+        self.received.push_str(std::str::from_utf8(data).unwrap());
+        Ok(())
+    }
+
+    async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, IoDeviceError> {
+        if let Some(input) = self.input.take() {
+            data[..input.len()].copy_from_slice(input.as_bytes());
+            return Ok(input.len());
+        }
+
+        self.reads += 1;
+        assert!(self.reads < 100, "the idle timeout never expired");
+        Err(IoDeviceError::WouldBlock)
+    }
+}
+
+struct ResettingErrorRenderer {}
+impl<IO: IoDevice> ErrorRenderer<IO> for ResettingErrorRenderer {
+    async fn render(
+        &self,
+        error: MenuError,
+        context: &ErrorContext<'_>,
+        output: &mut Output<'_, IO>,
+    ) -> Result<(), MenuError> {
+        match error {
+            MenuError::IdleTimeout => outwriteln!(output, "Session reset"),
+            other => DefaultErrorRenderer {}.render(other, context, output).await,
+        }
+    }
+}
+
+struct SilentErrorRenderer {}
+impl<IO: IoDevice> ErrorRenderer<IO> for SilentErrorRenderer {
+    async fn render(
+        &self,
+        _error: MenuError,
+        _context: &ErrorContext<'_>,
+        _output: &mut Output<'_, IO>,
+    ) -> Result<(), MenuError> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    version: u32,
+    overflowed: bool,
+    factory_mode: bool,
+}
+
+fn build_menu<'d>(
+    device: &'d mut MockIo,
+    state: &'d mut State,
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+) -> Result<impl Menu<MockIo, State> + use<'d>, RegistrationError> {
+    make_menu(device, state, input_buffer, output_buffer)
+        .with_command::<TestCommand>()?
+        .with_command::<VersionCommand>()?
+        .with_command::<OverflowCommand>()?
+        .with_command::<HelloCommand>()?
+        .with_command::<HiddenCommand>()
+}
+
+#[tokio::test]
+async fn prints_normal_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> test:     Tests stuff\n");
+    assert_eq!(device.read(), "> version:  Shows version\n");
+    assert_eq!(device.read(), "> overflow: Crashes\n");
+    assert_eq!(device.read(), "> hello:    Says hello\n");
+
+    // Hidden command should NOT appear
+    assert!(device.received.iter().all(|s| !s.contains("secret")));
+}
+
+#[tokio::test]
+async fn prints_alphabetical_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_help(HelpCommand {
+        order: HelpOrder::Alphabetical,
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> hello:    Says hello\n");
+    assert_eq!(device.read(), "> overflow: Crashes\n");
+    assert_eq!(device.read(), "> test:     Tests stuff\n");
+    assert_eq!(device.read(), "> version:  Shows version\n");
+}
+
+#[tokio::test]
+async fn wraps_long_help_strings() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<HelloCommand>()
+    .unwrap()
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_help(HelpCommand {
+        wrap_width: Some(20),
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > hello:   Says\n\
+         \x20          hello\n\
+         > version: Shows\n\
+         \x20          version\n"
+    );
+}
+
+fn build_categorized_menu<'d>(
+    device: &'d mut MockIo,
+    state: &'d mut State,
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+) -> Result<impl Menu<MockIo, State> + use<'d>, RegistrationError> {
+    make_menu(device, state, input_buffer, output_buffer)
+        .with_command::<TestCommand>()?
+        .with_command::<StatusCommand>()?
+        .with_command::<PingCommand>()?
+        .with_command::<DumpCommand>()?
+        .with_command::<HelloCommand>()
+}
+
+#[tokio::test]
+async fn groups_help_by_category() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_categorized_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > test:   Tests stuff\n\
+         > hello:  Says hello\n\
+         \nDiagnostics:\n\
+         > status: Shows status\n\
+         > dump:   Dumps many lines\n\
+         \nNetwork:\n\
+         > ping:   Does nothing\n"
+    );
+}
+
+#[tokio::test]
+async fn groups_alphabetical_help_by_category() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_categorized_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_help(HelpCommand {
+        order: HelpOrder::Alphabetical,
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > hello:  Says hello\n\
+         > test:   Tests stuff\n\
+         \nDiagnostics:\n\
+         > dump:   Dumps many lines\n\
+         > status: Shows status\n\
+         \nNetwork:\n\
+         > ping:   Does nothing\n"
+    );
+}
+
+#[tokio::test]
+async fn prints_help_for_category() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help diagnostics\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_categorized_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "Diagnostics:\n\
+         > status: Shows status\n\
+         > dump:   Dumps many lines\n"
+    );
+}
+
+#[tokio::test]
+async fn supports_renamed_and_restyled_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("?\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_help(HelpCommand {
+        name: "?",
+        header: "Commands:",
+        prefix: "  ",
+        ..Default::default()
+    })
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_command::<HelloCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Commands:\n");
+    assert_eq!(device.read(), "  test:  Tests stuff\n");
+    assert_eq!(device.read(), "  hello: Says hello\n");
+    assert_eq!(
+        device.read(),
+        "Unknown command 'help'. Did you mean 'hello'?\n"
+    );
+}
+
+#[tokio::test]
+async fn supports_removing_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .without_help();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read(),
+        "Unknown command 'help'. Did you mean 'hello'?\n"
+    );
+}
+
+#[tokio::test]
+async fn treats_help_like_other_commands() {
+    let mut device = MockIo::new();
+    device.queue_to_send("?\n");
+    device.queue_to_send("help help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let mut dispatch_table = [DispatchEntry::EMPTY; 4];
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_command_instance(
+        FnCommand::new("debug", "Debugs", async |_args, output, _state| {
+            outwriteln!(output, "Debugging")
+        })
+        .with_category("Debug"),
+    )
+    .unwrap()
+    .with_dispatch_table(&mut dispatch_table)
+    .unwrap()
+    .with_help(HelpCommand {
+        aliases: &["?"],
+        category: Some("General"),
+        hidden: false,
+        category_prefix: "[",
+        category_suffix: "]",
+        aliases_label: "Also: ",
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > version: Shows version\n\
+         \n\
+         [General]\n\
+         > help:    Lists the available commands\n\
+         \n\
+         [Debug]\n\
+         > debug:   Debugs\n\
+         > help: Lists the available commands\n\
+         Also: ?\n"
+    );
+}
+
+#[tokio::test]
+async fn requires_privilege_level_of_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+    device.queue_to_send("login user\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_protected_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_help(HelpCommand {
+        required_level: 1,
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "Unknown command\n\
+         Logged in\n\
+         AVAILABLE COMMANDS:\n\n\
+         > version: Shows version\n"
+    );
+}
+
+#[test]
+fn rejects_help_aliases_clashing_with_commands() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_help(HelpCommand {
+        aliases: &["?"],
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(matches!(
+        menu.with_fn("?", "Asks", async |_args, _output, _state| Ok(())),
+        Err(RegistrationError::ReservedName("?"))
+    ));
+
+    let mut device = MockIo::new();
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<VersionCommand>()
+    .unwrap();
+    assert!(matches!(
+        menu.with_help(HelpCommand {
+            aliases: &["version"],
+            ..Default::default()
+        }),
+        Err(RegistrationError::DuplicateName("version"))
+    ));
+}
+
+#[tokio::test]
+async fn frees_help_name_when_removing_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .without_help()
+    .with_command::<HelpNameCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn supports_help_without_hidden_flag() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help --hidden\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_help(HelpCommand {
+        hidden_flag: None,
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    // The flag is treated like an unknown command name instead
+    assert_eq!(device.read(), "Unknown command\n");
+    assert!(device.received.iter().all(|s| !s.contains("secret")));
+}
+
+#[test]
+fn rejects_help_name_used_by_command() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        build_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .unwrap()
+        .with_help(HelpCommand {
+            name: "test",
+            ..Default::default()
+        }),
+        Err(RegistrationError::DuplicateName("test"))
+    ));
+}
+
+#[tokio::test]
+async fn prints_hidden_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help --hidden\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    // Hidden command should appear
+    assert!(device.received.iter().any(|s| s.contains("secret")));
+}
+
+#[tokio::test]
+async fn prints_detailed_command_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help hello\n");
+    device.queue_to_send("help secret\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "> hello: Says hello\n\nUsage: hello <name>\nExample: hello World\n\
+         > secret: A hidden command\n"
+    );
+}
+
+#[tokio::test]
+async fn prints_aliases_in_detailed_help() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help st\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<StatusCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "> status: Shows status\nAliases: st, stat\n"
+    );
+}
+
+#[tokio::test]
+async fn rejects_detailed_help_for_unknown_command() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help verison\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read(),
+        "Unknown command 'verison'. Did you mean 'version'?\n"
+    );
+}
+
+#[tokio::test]
+async fn supports_simple_command() {
+    let mut device = MockIo::new();
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn supports_formatting() {
+    let mut device = MockIo::new();
+    device.queue_to_send("version\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), VERSION_RESPONSE);
+}
+
+#[tokio::test]
+async fn handles_multiple_requests() {
+    let mut device = MockIo::new();
+    device.queue_to_send("test\n");
+    device.queue_to_send("version\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(device.read(), VERSION_RESPONSE);
+}
+
+#[tokio::test]
+async fn handles_requests_after_error() {
+    let mut device = MockIo::new();
+    device.queue_to_send("unkown\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn supports_inputs_in_pieces() {
+    let mut device = MockIo::new();
+    device.queue_to_send("tes");
+    device.queue_to_send("t\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn supports_two_inputs_in_pieces() {
+    let mut device = MockIo::new();
+    device.queue_to_send("test\nver");
+    device.queue_to_send("sion");
+    device.queue_to_send("\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(device.read(), VERSION_RESPONSE);
+}
+
+#[tokio::test]
+async fn supports_two_inputs_at_once() {
+    let mut device = MockIo::new();
+    device.queue_to_send("version\ntest\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), VERSION_RESPONSE);
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn handles_unknown_command() {
+    let mut device = MockIo::new();
+    device.queue_to_send("unknown\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Unknown command\n");
+}
+
+#[tokio::test]
+async fn handles_input_buffer_overflow() {
+    let mut device = MockIo::new();
+    device.queue_to_send("very long string ");
+    device.queue_to_send("that will overflow\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 20];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    // The device dropped the line terminator along with the rest of the line, so all input up
+    // to the next terminator is dropped, including the following line
+    assert_eq!(device.read(), "Input buffer overflowed & dumped\n");
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn keeps_next_line_after_device_overflow_between_lines() {
+    let mut device = MockIo::new();
+    device.queue_error(IoDeviceError::BufferOverflow);
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Input buffer overflowed & dumped\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn discards_remainder_of_overflowing_line() {
+    let mut device = MockIo::with_split_packets();
+    device.queue_to_send("hello this line is way too long\ntest\n");
+
+    let mut input_buffer = [0; 16];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Input buffer overflowed & dumped\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn discards_remainder_after_device_overflow() {
+    let mut device = MockIo::new();
+    device.queue_to_send("hello wor");
+    device.queue_error(IoDeviceError::BufferOverflow);
+    device.queue_to_send("ld test\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Input buffer overflowed & dumped\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn skips_lines_with_invalid_utf8() {
+    let mut device = MockIo::new();
+    device.queue_to_send("test\n");
+    device.queue_bytes_to_send(b"te\xffst\nversion\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(device.read(), "Input UTF8 error\n");
+    assert_eq!(device.read(), VERSION_RESPONSE);
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn handles_output_buffer_overflow() {
+    let mut device = MockIo::new();
+    device.queue_to_send("overflow\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 5];
+    let mut state = State::default();
+    assert!(!state.overflowed);
+
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+
+    menu.run().await.unwrap();
+    assert!(state.overflowed);
+}
+
+#[tokio::test]
+async fn handles_command_arguments() {
+    let mut device = MockIo::new();
+    device.queue_to_send("hello Testing Person\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Hello Testing Person!\n");
+}
+
+#[tokio::test]
+async fn hidden_command_can_be_executed() {
+    let mut device = MockIo::new();
+    device.queue_to_send("secret\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), SECRET_RESPONSE);
+}
+
+#[tokio::test]
+async fn pager_pauses_long_output() {
+    let mut device = MockIo::new();
+    device.queue_to_send("dump\n");
+    device.queue_to_send(" ");
+    device.queue_to_send("\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<DumpCommand>()
+    .unwrap()
+    .with_pager(2);
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "Line 1\nLine 2\n--More--\r        \rLine 3\nLine 4\n--More--\r        \rLine 5\n"
+    );
+}
+
+#[tokio::test]
+async fn pager_prompt_ends_idle_session() {
+    let mut device = MockIo::new();
+    device.queue_to_send("dump\ntest\n");
+    device.queue_idle();
+    device.queue_to_send("version\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<DumpCommand>()
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_pager(2)
+    .with_error_renderer(ResettingErrorRenderer {})
+    .with_idle_timeout(ImmediateTimer {}, 60_000);
+    menu.run().await.unwrap();
+
+    // The rest of the output and the input typed ahead are dropped with the session
+    assert_eq!(
+        device.read_all(),
+        "Line 1\nLine 2\n--More--Session reset\nVersion: 0\n"
+    );
+}
+
+#[tokio::test]
+async fn pager_can_abort_output() {
+    let mut device = MockIo::new();
+    device.queue_to_send("dump\n");
+    device.queue_to_send("q");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<DumpCommand>()
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_pager(2);
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        format!("Line 1\nLine 2\n--More--\r        \r{TEST_RESPONSE}")
+    );
+}
+
+#[tokio::test]
+async fn prints_command_errors() {
+    let mut device = MockIo::new();
+    device.queue_to_send("sensor\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<FailingCommand>()
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Error: Sensor read failed\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn supports_custom_error_renderer() {
+    let mut device = MockIo::new();
+    device.queue_to_send("stauts now\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_error_renderer(TerseErrorRenderer {});
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "ERR unknown 'stauts now'\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+}
+
+#[tokio::test]
+async fn custom_error_renderer_can_stop_menu() {
+    let mut device = MockIo::new();
+    device.queue_to_send("sensor\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<FailingCommand>()
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_error_renderer(TerseErrorRenderer {});
+
+    let res = menu.run().await;
+    assert_eq!(res, Err(MenuError::Command("Sensor read failed")));
+    assert!(device.received.is_empty());
+}
+
+#[tokio::test]
+async fn stops_on_disconnect_even_if_renderer_ignores_it() {
+    let mut device = MockIo::new();
+    device.queue_to_send("sensor\n");
+    device.queue_to_send("test\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<FailingCommand>()
+    .unwrap()
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_error_renderer(SilentErrorRenderer {});
+
+    assert_eq!(menu.run().await, Ok(()));
+    assert_eq!(device.read_all(), "Testing 123!\n");
+}
+
+#[tokio::test]
+async fn suggests_similar_command() {
+    let mut device = MockIo::new();
+    device.queue_to_send("verison\n");
+    device.queue_to_send("secrte\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read(),
+        "Unknown command 'verison'. Did you mean 'version'?\n"
+    );
+
+    // Hidden commands should never be suggested
+    assert_eq!(device.read(), "Unknown command\n");
+}
+
+#[test]
+fn rejects_invalid_command_names() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<EmptyNameCommand>(),
+        Err(RegistrationError::EmptyName)
+    ));
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<SpacedNameCommand>(),
+        Err(RegistrationError::InvalidName("two words"))
+    ));
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<TabbedNameCommand>(),
+        Err(RegistrationError::InvalidName("two\twords"))
+    ));
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<HelpNameCommand>(),
+        Err(RegistrationError::ReservedName("help"))
+    ));
+}
+
+#[test]
+fn rejects_duplicate_command_names() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    let res = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_command::<VersionCommand>();
+    assert!(matches!(
+        res,
+        Err(RegistrationError::DuplicateName("version"))
+    ));
+}
+
+#[tokio::test]
+async fn supports_command_aliases() {
+    let mut device = MockIo::new();
+    device.queue_to_send("st\n");
+    device.queue_to_send("status\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<StatusCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), STATUS_RESPONSE);
+    assert_eq!(device.read(), STATUS_RESPONSE);
+}
+
+#[test]
+fn rejects_name_clashing_with_earlier_alias() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<StatusCommand>()
+        .unwrap()
+        .with_command::<StNameCommand>(),
+        Err(RegistrationError::DuplicateName("st"))
+    ));
+}
+
+#[test]
+fn rejects_alias_clashing_with_earlier_name() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<StNameCommand>()
+        .unwrap()
+        .with_command::<StatusCommand>(),
+        Err(RegistrationError::DuplicateName("st"))
+    ));
+}
+
+#[test]
+fn rejects_repeated_aliases() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<RepeatedAliasCommand>(),
+        Err(RegistrationError::DuplicateName("again"))
+    ));
+}
+
+#[tokio::test]
+async fn supports_command_instances() {
+    let mut device = MockIo::new();
+    device.queue_to_send("read0 12\n");
+    device.queue_to_send("read1\n");
+    device.queue_to_send("read0 13\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command_instance(ReadCommand {
+        name: "read0",
+        bus: 0,
+        reads: 0,
+    })
+    .unwrap()
+    .with_command_instance(ReadCommand {
+        name: "read1",
+        bus: 1,
+        reads: 0,
+    })
+    .unwrap()
+    .with_command::<VersionCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Bus 0 register 12: read 1\n");
+    assert_eq!(device.read(), "Bus 1 register 0: read 1\n");
+    assert_eq!(device.read(), "Bus 0 register 13: read 2\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > read0:   Reads a register\n\
+         > read1:   Reads a register\n\
+         > version: Shows version\n"
+    );
+}
+
+#[test]
+fn rejects_duplicate_command_instances() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command_instance(ReadCommand {
+            name: "read",
+            bus: 0,
+            reads: 0,
+        })
+        .unwrap()
+        .with_command_instance(ReadCommand {
+            name: "read",
+            bus: 1,
+            reads: 0,
+        }),
+        Err(RegistrationError::DuplicateName("read"))
+    ));
+}
+
+#[tokio::test]
+async fn supports_closure_commands() {
+    let mut device = MockIo::new();
+    device.queue_to_send("bump 5\n");
+    device.queue_to_send("version\n");
+    device.queue_to_send("debug\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("help --hidden\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_fn("bump", "Bumps version", async |args, output, state| {
+        state.version += args.and_then(|a| a.parse().ok()).unwrap_or(1);
+        outwriteln!(output, "Bumped")
+    })
+    .unwrap()
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_command_instance(
+        FnCommand::new("debug", "Debugs", async |_args, output, _state| {
+            outwriteln!(output, "Debugging")
+        })
+        .with_hidden(true),
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Bumped\n");
+    assert_eq!(device.read(), "Version: 5\n");
+    assert_eq!(device.read(), "Debugging\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > bump:    Bumps version\n\
+         > version: Shows version\n\
+         AVAILABLE COMMANDS:\n\n\
+         > help:    Lists the available commands\n\
+         > bump:    Bumps version\n\
+         > version: Shows version\n\
+         > debug:   Debugs\n"
+    );
+}
+
+#[test]
+fn rejects_invalid_closure_command_names() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_fn(
+            "two words",
+            "Does nothing",
+            async |_args, _output, _state| Ok(())
+        ),
+        Err(RegistrationError::InvalidName("two words"))
+    ));
+}
+
+#[tokio::test]
+async fn dispatches_through_table() {
+    let mut device = MockIo::new();
+    device.queue_to_send("version\n");
+    device.queue_to_send("st\n");
+    device.queue_to_send("stat\n");
+    device.queue_to_send("test\n");
+    device.queue_to_send("verison\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let mut dispatch_table = [DispatchEntry::EMPTY; 5];
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_dispatch_table(&mut dispatch_table)
+    .unwrap()
+    .with_command::<StatusCommand>()
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Version: 0\n");
+    assert_eq!(device.read(), STATUS_RESPONSE);
+    assert_eq!(device.read(), STATUS_RESPONSE);
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(
+        device.read(),
+        "Unknown command 'verison'. Did you mean 'version'?\n"
+    );
+}
+
+#[test]
+fn rejects_commands_that_do_not_fit_in_dispatch_table() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let mut dispatch_table = [DispatchEntry::EMPTY; 3];
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_dispatch_table(&mut dispatch_table)
+        .unwrap()
+        .with_command::<StatusCommand>(),
+        Err(RegistrationError::DispatchTableFull("stat"))
+    ));
+}
+
+fn build_modular_menu<'d>(
+    device: &'d mut MockIo,
+    state: &'d mut State,
+    input_buffer: &'d mut [u8],
+    output_buffer: &'d mut [u8],
+) -> Result<impl Menu<MockIo, State> + use<'d>, RegistrationError> {
+    make_menu(device, state, input_buffer, output_buffer)
+        .with_module::<ImuCommands>()?
+        .with_module::<PowerCommands>()?
+        .with_command::<VersionCommand>()
+}
+
+#[tokio::test]
+async fn supports_modules() {
+    let mut device = MockIo::new();
+    device.queue_to_send("imu read 7\n");
+    device.queue_to_send("imu version\n");
+    device.queue_to_send("version\n");
+    device.queue_to_send("read\n");
+    device.queue_to_send("imu test\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_modular_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Bus 2 register 7: read 1\n");
+    assert_eq!(device.read(), "Version: 0\n");
+    assert_eq!(device.read(), "Version: 0\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > version:     Shows version\n\
+         \nSensors:\n\
+         > imu read:    Reads a register\n\
+         > imu version: Shows version\n\
+         \nPower:\n\
+         > test:        Tests stuff\n\
+         \nDiagnostics:\n\
+         > status:      Shows status\n"
+    );
+}
+
+#[tokio::test]
+async fn prints_help_for_modules() {
+    let mut device = MockIo::new();
+    device.queue_to_send("help imu\n");
+    device.queue_to_send("help imu read\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_modular_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_help(HelpCommand {
+        order: HelpOrder::Alphabetical,
+        ..Default::default()
+    })
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(
+        device.read_all(),
+        "> imu read:    Reads a register\n\
+         > imu version: Shows version\n\
+         > imu read: Reads a register\n"
+    );
+}
+
+#[tokio::test]
+async fn dispatches_modules_through_table() {
+    let mut device = MockIo::new();
+    device.queue_to_send("imu read 3\n");
+    device.queue_to_send("version\n");
+    device.queue_to_send("imu stat\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let mut dispatch_table = [DispatchEntry::EMPTY; 8];
+    let menu = build_modular_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_dispatch_table(&mut dispatch_table)
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Bus 2 register 3: read 1\n");
+    assert_eq!(device.read(), "Version: 0\n");
+    assert_eq!(device.read(), "Unknown command\n");
+}
+
+#[test]
+fn rejects_module_prefix_clashing_with_command() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command_instance(ReadCommand {
+            name: "imu",
+            bus: 0,
+            reads: 0,
+        })
+        .unwrap()
+        .with_module::<ImuCommands>(),
+        Err(RegistrationError::DuplicateName("imu"))
+    ));
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_module::<ImuCommands>()
+        .unwrap()
+        .with_command_instance(ReadCommand {
+            name: "imu",
+            bus: 0,
+            reads: 0,
+        }),
+        Err(RegistrationError::DuplicateName("imu"))
+    ));
+}
+
+fn build_counter_menu<IO: IoDevice>() -> Result<impl SubMenu<IO, u32>, RegistrationError> {
+    make_submenu::<IO, u32>()
+        .with_fn(
+            "show",
+            "Shows the counter",
+            async |_args, output, counter: &mut u32| outwriteln!(output, "Counter: {}", counter),
+        )?
+        .with_fn(
+            "bump",
+            "Increments the counter",
+            async |_args, _output, counter: &mut u32| {
+                *counter += 1;
+                Ok(())
+            },
+        )
+}
+
+#[tokio::test]
+async fn mounts_submenus() {
+    let mut device = MockIo::new();
+    device.queue_to_send("counter bump\n");
+    device.queue_to_send("counter bump\n");
+    device.queue_to_send("version\n");
+    device.queue_to_send("counter show\n");
+    device.queue_to_send("counter shw\n");
+    device.queue_to_send("counter\n");
+    device.queue_to_send("counter help bump\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_submenu(
+        "counter",
+        "Controls the counter",
+        build_counter_menu().unwrap(),
+        |state: &mut State| &mut state.version,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Version: 2\n");
+    assert_eq!(device.read(), "Counter: 2\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > show: Shows the counter\n\
+         > bump: Increments the counter\n\
+         > bump: Increments the counter\n\
+         AVAILABLE COMMANDS:\n\n\
+         > version: Shows version\n\
+         > counter: Controls the counter\n"
+    );
+}
+
+#[test]
+fn rejects_submenu_name_clashing_with_command() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_command::<VersionCommand>()
+        .unwrap()
+        .with_submenu(
+            "version",
+            "Controls the counter",
+            build_counter_menu().unwrap(),
+            |state: &mut State| &mut state.version,
+        ),
+        Err(RegistrationError::DuplicateName("version"))
+    ));
+}
+
+#[tokio::test]
+async fn registers_modules_in_submenus() {
+    let mut device = MockIo::new();
+    device.queue_to_send("power status\n");
+    device.queue_to_send("power\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_submenu(
+        "power",
+        "Manages power",
+        make_submenu().with_module::<PowerCommands>().unwrap(),
+        |state: &mut State| state,
+    )
+    .unwrap();
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), STATUS_RESPONSE);
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         Power:\n\
+         > test:   Tests stuff\n\
+         \n\
+         Diagnostics:\n\
+         > status: Shows status\n"
+    );
 }
 
-#[derive(Default)]
-struct State {
-    version: u32,
-    overflowed: bool,
+#[tokio::test]
+async fn runs_hooks_around_commands() {
+    let mut device = MockIo::new();
+    device.queue_to_send("version\n");
+    device.queue_to_send("sensor\n");
+    device.queue_to_send("help\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_command::<FailingCommand>()
+    .unwrap()
+    .with_hook(LogHook {})
+    .with_hook(SafeModeHook {});
+    menu.run().await.unwrap();
+
+    assert_eq!(device.read(), "Running version\n");
+    assert_eq!(device.read(), "Version: 0\n");
+    assert_eq!(device.read(), "Finished version: ok\n");
+    assert_eq!(device.read(), "Running sensor\n");
+    assert_eq!(device.read(), "Finished sensor: failed\n");
+    assert_eq!(device.read(), "Error: Not available in safe mode\n");
+    assert_eq!(
+        device.read_all(),
+        "Running help\n\
+         AVAILABLE COMMANDS:\n\n\
+         > version: Shows version\n\
+         > sensor:  Reads a broken sensor\n\
+         Finished help: ok\n"
+    );
 }
 
-fn build_menu<'d>(
+fn build_protected_menu<'d>(
     device: &'d mut MockIo,
     state: &'d mut State,
     input_buffer: &'d mut [u8],
     output_buffer: &'d mut [u8],
-) -> impl Menu<MockIo, State> + use<'d> {
+) -> Result<impl Menu<MockIo, State> + use<'d>, RegistrationError> {
     make_menu(device, state, input_buffer, output_buffer)
-        .with_command::<TestCommand>()
-        .with_command::<VersionCommand>()
-        .with_command::<OverflowCommand>()
-        .with_command::<HelloCommand>()
-        .with_command::<HiddenCommand>()
+        .with_command::<VersionCommand>()?
+        .with_command::<CalibrateCommand>()?
+        .with_login(TestVerifier {}, 3)
 }
 
 #[tokio::test]
-async fn prints_normal_help() {
+async fn requires_login_for_privileged_commands() {
     let mut device = MockIo::new();
+    device.queue_to_send("calibrate\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("login user\n");
+    device.queue_to_send("calibrate\n");
+    device.queue_to_send("login service\n");
+    device.queue_to_send("calibrate\n");
     device.queue_to_send("help\n");
+    device.queue_to_send("logout\n");
+    device.queue_to_send("help calibrate\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
-
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
+    assert_eq!(device.read(), "Unknown command\n");
     assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
-    assert_eq!(device.read(), "> hello: Says hello\n");
-    assert_eq!(device.read(), "> overflow: Crashes\n");
     assert_eq!(device.read(), "> version: Shows version\n");
-    assert_eq!(device.read(), "> test: Tests stuff\n");
-
-    // Hidden command should NOT appear
-    assert!(device.received.iter().all(|s| !s.contains("secret")));
+    assert_eq!(device.read(), "Logged in\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(device.read(), "Logged in\n");
+    assert_eq!(device.read(), "Calibrated\n");
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> version:   Shows version\n");
+    assert_eq!(device.read(), "> calibrate: Calibrates the sensors\n");
+    assert_eq!(device.read(), "Logged out\n");
+    assert_eq!(device.read_all(), "Unknown command\n");
 }
 
 #[tokio::test]
-async fn prints_hidden_help() {
+async fn requires_login_for_privileged_submenus() {
     let mut device = MockIo::new();
-    device.queue_to_send("help --hidden\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("service\n");
+    device.queue_to_send("login service\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("service calibrate\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
-
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = make_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .with_command::<VersionCommand>()
+    .unwrap()
+    .with_submenu(
+        "service",
+        "Service commands",
+        make_submenu().with_command::<CalibrateCommand>().unwrap(),
+        |state: &mut State| state,
+    )
+    .unwrap()
+    .with_login(TestVerifier {}, 3)
+    .unwrap();
     menu.run().await.unwrap();
 
-    // Hidden command should appear
-    assert!(device.received.iter().any(|s| s.contains("secret")));
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> version: Shows version\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(device.read(), "Logged in\n");
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> version: Shows version\n");
+    assert_eq!(device.read(), "> service: Service commands\n");
+    assert_eq!(device.read_all(), "Calibrated\n");
 }
 
 #[tokio::test]
-async fn supports_simple_command() {
+async fn runs_hooks_around_login_and_logout() {
     let mut device = MockIo::new();
-    device.queue_to_send("test\n");
+    device.queue_to_send("login user\n");
+    device.queue_to_send("logout\n");
+    device.queue_to_send("login\n");
+    device.queue_to_send("service\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap()
+    .with_hook(LogHook {});
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), TEST_RESPONSE);
+    // Hooks never get to see the password
+    assert_eq!(
+        device.read_all(),
+        "Running login\n\
+         Logged in\n\
+         Finished login: ok\n\
+         Running logout\n\
+         Logged out\n\
+         Finished logout: ok\n\
+         Running login\n\
+         Password: Finished login: ok\n\
+         Running login\n\
+         Logged in\n\
+         Finished login: ok\n"
+    );
 }
 
 #[tokio::test]
-async fn supports_formatting() {
+async fn lets_hooks_veto_login() {
     let mut device = MockIo::new();
-    device.queue_to_send("version\n");
+    device.queue_to_send("login\n");
+    device.queue_to_send("service\n");
+    device.queue_to_send("calibrate\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap()
+    .with_hook(SafeModeHook {});
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), VERSION_RESPONSE);
+    // The line after a vetoed login is not taken as the password
+    assert_eq!(
+        device.read_all(),
+        "Error: Not available in safe mode\n\
+         Unknown command\n\
+         Unknown command\n"
+    );
 }
 
 #[tokio::test]
-async fn handles_multiple_requests() {
+async fn prompts_for_password() {
     let mut device = MockIo::new();
-    device.queue_to_send("test\n");
-    device.queue_to_send("version\n");
+    device.queue_to_send("login\n");
+    device.queue_to_send("wrong\n");
+    device.queue_to_send("login\n");
+    device.queue_to_send("service\n");
+    device.queue_to_send("calibrate\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), TEST_RESPONSE);
-    assert_eq!(device.read(), VERSION_RESPONSE);
+    assert_eq!(
+        device.read_all(),
+        "Password: Error: Wrong password\n\
+         Password: Logged in\n\
+         Calibrated\n"
+    );
 }
 
 #[tokio::test]
-async fn handles_requests_after_error() {
+async fn locks_login_after_failed_attempts() {
     let mut device = MockIo::new();
-    device.queue_to_send("unkown\n");
-    device.queue_to_send("test\n");
+    device.queue_to_send("login guess\n");
+    device.queue_to_send("login 1234\n");
+    device.queue_to_send("login admin\n");
+    device.queue_to_send("login service\n");
+    device.queue_to_send("calibrate\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
+    assert_eq!(device.read(), "Error: Wrong password\n");
+    assert_eq!(device.read(), "Error: Wrong password\n");
+    assert_eq!(device.read(), "Error: Wrong password\n");
+    assert_eq!(
+        device.read(),
+        "Error: Login locked after too many failed attempts\n"
+    );
     assert_eq!(device.read(), "Unknown command\n");
-    assert_eq!(device.read(), TEST_RESPONSE);
 }
 
 #[tokio::test]
-async fn supports_inputs_in_pieces() {
+async fn keeps_password_out_of_error_context() {
     let mut device = MockIo::new();
-    device.queue_to_send("tes");
-    device.queue_to_send("t\n");
+    device.queue_to_send("login secret\n");
+    device.queue_to_send("login\n");
+    device.queue_to_send("secret\n");
+    device.queue_to_send("calibrate now\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = build_protected_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap()
+    .with_error_renderer(EchoLineErrorRenderer {});
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(
+        device.read_all(),
+        "ERR\n\
+         Password: ERR\n\
+         ERR in 'calibrate now'\n"
+    );
+}
+
+#[test]
+fn rejects_commands_named_like_login() {
+    let mut device = MockIo::new();
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_login(TestVerifier {}, 3)
+        .unwrap()
+        .with_fn(
+            "logout",
+            "Logs out",
+            async |_args, output, _state: &mut State| { outwriteln!(output, "Bye") }
+        ),
+        Err(RegistrationError::ReservedName("logout"))
+    ));
+
+    assert!(matches!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        )
+        .with_fn(
+            "login",
+            "Logs in",
+            async |_args, output, _state: &mut State| { outwriteln!(output, "Hi") }
+        )
+        .unwrap()
+        .with_login(TestVerifier {}, 3),
+        Err(RegistrationError::DuplicateName("login"))
+    ));
 }
 
 #[tokio::test]
-async fn supports_two_inputs_in_pieces() {
+async fn applies_state_predicates_of_fn_commands() {
     let mut device = MockIo::new();
-    device.queue_to_send("test\nver");
-    device.queue_to_send("sion");
-    device.queue_to_send("\n");
+    device.queue_to_send("trim\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("factory\n");
+    device.queue_to_send("trim\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = make_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .with_command_instance(
+        FnCommand::new(
+            "trim",
+            "Trims the oscillator",
+            async |_args, output, _state| outwriteln!(output, "Trimmed"),
+        )
+        .with_hidden_when(|state: &State| !state.factory_mode)
+        .with_enabled_when(|state: &State| state.factory_mode),
+    )
+    .unwrap()
+    .with_fn(
+        "factory",
+        "Enters factory mode",
+        async |_args, _output, state: &mut State| {
+            state.factory_mode = true;
+            Ok(())
+        },
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), TEST_RESPONSE);
-    assert_eq!(device.read(), VERSION_RESPONSE);
+    assert_eq!(
+        device.read_all(),
+        "Command not available in current mode\n\
+         AVAILABLE COMMANDS:\n\
+         \n\
+         > factory: Enters factory mode\n\
+         Trimmed\n"
+    );
 }
 
 #[tokio::test]
-async fn supports_two_inputs_at_once() {
+async fn applies_state_dependent_predicates() {
     let mut device = MockIo::new();
-    device.queue_to_send("version\ntest\n");
+    device.queue_to_send("trim\n");
+    device.queue_to_send("trm\n");
+    device.queue_to_send("help\n");
+    device.queue_to_send("factory\n");
+    device.queue_to_send("trm\n");
+    device.queue_to_send("trim\n");
+    device.queue_to_send("help\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let menu = make_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .with_command::<TrimCommand>()
+    .unwrap()
+    .with_fn(
+        "factory",
+        "Enters factory mode",
+        async |_args, _output, state: &mut State| {
+            state.factory_mode = true;
+            Ok(())
+        },
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), VERSION_RESPONSE);
-    assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(device.read(), "Command not available in current mode\n");
+    assert_eq!(device.read(), "Unknown command\n");
+    assert_eq!(device.read(), "AVAILABLE COMMANDS:\n\n");
+    assert_eq!(device.read(), "> factory: Enters factory mode\n");
+    assert_eq!(
+        device.read(),
+        "Unknown command 'trm'. Did you mean 'trim'?\n"
+    );
+    assert_eq!(device.read(), "Trimmed\n");
+    assert_eq!(
+        device.read_all(),
+        "AVAILABLE COMMANDS:\n\n\
+         > trim:    Trims the oscillator\n\
+         > factory: Enters factory mode\n"
+    );
 }
 
-#[tokio::test]
-async fn handles_unknown_command() {
+#[test]
+fn run_future_is_bounded_by_largest_command() {
     let mut device = MockIo::new();
-    device.queue_to_send("unknown\n");
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+
+    let single = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<TestCommand>()
+    .unwrap();
+    let single_size = size_of_val(&single.run());
+
+    let many = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_command::<StatusCommand>()
+    .unwrap()
+    .with_command::<DumpCommand>()
+    .unwrap()
+    .with_command::<FailingCommand>()
+    .unwrap();
+    let many_size = size_of_val(&many.run());
+    assert_eq!(many_size, single_size);
+
+    let buffering = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<BufferingCommand>()
+    .unwrap();
+    let buffering_size = size_of_val(&buffering.run());
+    assert!(buffering_size > single_size);
+
+    let many_buffering = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_command::<BufferingCommand>()
+    .unwrap()
+    .with_command::<StatusCommand>()
+    .unwrap();
+    assert_eq!(size_of_val(&many_buffering.run()), buffering_size);
+}
 
+#[test]
+fn dispatch_table_does_not_grow_run_future() {
+    let mut device = MockIo::new();
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
-    let menu = build_menu(
+    let mut dispatch_table = [DispatchEntry::EMPTY; 64];
+
+    let chain = build_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
+    )
+    .unwrap();
+    let chain_size = size_of_val(&chain.run());
+
+    let table = build_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_dispatch_table(&mut dispatch_table)
+    .unwrap();
+    // The menu only holds a reference to the entries and the number of entries in use
+    let table_size = size_of_val(&table.run());
+    assert!(table_size <= chain_size + size_of::<&mut [DispatchEntry]>() + size_of::<usize>());
+}
+
+/// Registers a closure command for every name, which prints its own name.
+macro_rules! with_echo_commands {
+    ($menu:expr, $($name:literal),*) => {
+        $menu
+            $(
+                .with_fn($name, "Echoes its name", async |_args, output, _state| {
+                    outwriteln!(output, $name)
+                })
+                .unwrap()
+            )*
+    };
+}
+
+#[tokio::test]
+async fn runs_every_command_of_larger_menus() {
+    let names = [
+        "c0", "c1", "c2", "c3", "c4", "c5", "c6", "c7", "c8", "c9", "c10",
+    ];
+    let mut device = MockIo::new();
+    for name in names.iter().rev() {
+        device.queue_to_send(name);
+        device.queue_to_send("\n");
+    }
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = with_echo_commands!(
+        make_menu(
+            &mut device,
+            &mut state,
+            &mut input_buffer,
+            &mut output_buffer,
+        ),
+        "c0",
+        "c1",
+        "c2",
+        "c3",
+        "c4",
+        "c5",
+        "c6",
+        "c7",
+        "c8",
+        "c9",
+        "c10"
     );
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), "Unknown command\n");
+    for name in names.iter().rev() {
+        assert_eq!(device.read(), format!("{name}\n"));
+    }
 }
 
 #[tokio::test]
-async fn handles_input_buffer_overflow() {
+async fn ends_idle_session() {
     let mut device = MockIo::new();
-    device.queue_to_send("very long string ");
-    device.queue_to_send("that will overflow\n");
     device.queue_to_send("test\n");
+    device.queue_idle();
+    device.queue_to_send("version\n");
 
-    let mut input_buffer = [0; 20];
+    let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
     let mut state = State::default();
     let menu = build_menu(
@@ -404,38 +3034,147 @@ async fn handles_input_buffer_overflow() {
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
-    menu.run().await.unwrap();
+    )
+    .unwrap()
+    .with_idle_timeout(ImmediateTimer {}, 60_000);
 
-    assert_eq!(device.read(), "Input buffer overflowed & dumped\n");
+    assert_eq!(menu.run().await, Err(MenuError::IdleTimeout));
     assert_eq!(device.read(), TEST_RESPONSE);
+    assert_eq!(device.read(), "Session timed out\n");
+    assert!(device.received.is_empty());
 }
 
 #[tokio::test]
-async fn handles_output_buffer_overflow() {
+async fn resets_idle_session() {
     let mut device = MockIo::new();
-    device.queue_to_send("overflow\n");
+    device.queue_to_send("tes");
+    device.queue_idle();
+    device.queue_to_send("version\n");
 
     let mut input_buffer = [0; 128];
-    let mut output_buffer = [0; 5];
+    let mut output_buffer = [0; 128];
     let mut state = State::default();
-    assert!(!state.overflowed);
-
     let menu = build_menu(
         &mut device,
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap()
+    .with_error_renderer(ResettingErrorRenderer {})
+    .with_idle_timeout(ImmediateTimer {}, 60_000);
+    menu.run().await.unwrap();
+
+    // The partial input must have been dropped by the reset
+    assert_eq!(device.read(), "Session reset\n");
+    assert_eq!(device.read(), VERSION_RESPONSE);
+}
 
+#[tokio::test]
+async fn password_prompt_ends_idle_session() {
+    let mut device = MockIo::new();
+    device.queue_to_send("login\n");
+    device.queue_idle();
+    device.queue_to_send("service\n");
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = build_protected_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .unwrap()
+    .with_error_renderer(ResettingErrorRenderer {})
+    .with_idle_timeout(ImmediateTimer {}, 60_000);
     menu.run().await.unwrap();
-    assert!(state.overflowed);
+
+    // After the reset, the password is no longer expected
+    assert_eq!(
+        device.read_all(),
+        "Password: Session reset\nUnknown command\n"
+    );
 }
 
 #[tokio::test]
-async fn handles_command_arguments() {
+async fn read_timeouts_do_not_restart_idle_timeout() {
+    let mut device = TimingOutIo::default();
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_idle_timeout(PollingTimer {}, 60_000);
+
+    assert_eq!(menu.run().await, Err(MenuError::IdleTimeout));
+    assert_eq!(device.received, "Session timed out\n");
+}
+
+#[tokio::test]
+async fn yields_while_device_would_block() {
+    let mut device = BlockingIo::default();
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<TestCommand>()
+    .unwrap()
+    .with_idle_timeout(PollingTimer {}, 60_000);
+
+    assert_eq!(menu.run().await, Err(MenuError::IdleTimeout));
+    assert_eq!(device.received, "Session timed out\n");
+}
+
+#[tokio::test]
+async fn pager_yields_while_device_would_block() {
+    let mut device = BlockingIo {
+        input: Some("dump\n"),
+        ..Default::default()
+    };
+
+    let mut input_buffer = [0; 128];
+    let mut output_buffer = [0; 128];
+    let mut state = State::default();
+    let menu = make_menu(
+        &mut device,
+        &mut state,
+        &mut input_buffer,
+        &mut output_buffer,
+    )
+    .with_command::<DumpCommand>()
+    .unwrap()
+    .with_pager(2)
+    .with_idle_timeout(PollingTimer {}, 60_000);
+
+    assert_eq!(menu.run().await, Err(MenuError::IdleTimeout));
+    assert_eq!(
+        device.received,
+        "Line 1\nLine 2\n--More--Session timed out\n"
+    );
+}
+
+#[tokio::test]
+async fn retries_transient_read_errors() {
     let mut device = MockIo::new();
-    device.queue_to_send("hello Testing Person\n");
+    device.queue_to_send("tes");
+    device.queue_error(IoDeviceError::WouldBlock);
+    device.queue_error(IoDeviceError::Timeout);
+    device.queue_to_send("t\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
@@ -445,16 +3184,23 @@ async fn handles_command_arguments() {
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), "Hello Testing Person!\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
+    assert!(device.received.is_empty());
 }
 
 #[tokio::test]
-async fn hidden_command_can_be_executed() {
+async fn reports_line_errors_and_continues() {
     let mut device = MockIo::new();
-    device.queue_to_send("secret\n");
+    device.queue_to_send("tes");
+    device.queue_error(IoDeviceError::Framing);
+    device.queue_to_send("t\nversion\n");
+    device.queue_error(IoDeviceError::Parity);
+    device.queue_error(IoDeviceError::Other(7));
+    device.queue_to_send("test\n");
 
     let mut input_buffer = [0; 128];
     let mut output_buffer = [0; 128];
@@ -464,8 +3210,13 @@ async fn hidden_command_can_be_executed() {
         &mut state,
         &mut input_buffer,
         &mut output_buffer,
-    );
+    )
+    .unwrap();
     menu.run().await.unwrap();
 
-    assert_eq!(device.read(), SECRET_RESPONSE);
+    assert_eq!(device.read(), "IO framing error\n");
+    assert_eq!(device.read(), VERSION_RESPONSE);
+    assert_eq!(device.read(), "IO parity error\n");
+    assert_eq!(device.read(), "IO error 7\n");
+    assert_eq!(device.read(), TEST_RESPONSE);
 }